@@ -0,0 +1,131 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::http_client;
+
+#[derive(Debug, clap::Args)]
+pub struct ApplyArgs {
+    #[arg(help = "YAML spec of the models to build")]
+    pub spec: String,
+
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Keep going after an entry fails, exiting with an error once all entries ran"
+    )]
+    pub continue_on_error: bool,
+
+    #[arg(
+        long,
+        help = "Number of entries built concurrently, overriding the spec's `jobs`"
+    )]
+    pub jobs: Option<usize>,
+
+    #[arg(long, env, help = "Username for pushing images")]
+    pub registry_username: Option<String>,
+
+    #[arg(long, env, help = "Password for pushing images")]
+    pub registry_password: Option<http_client::Secret>,
+}
+
+/// Models to build, each like a separate invocation of the launcher:
+///
+/// ```yaml
+/// jobs: 2
+/// models:
+///   - name: mistral
+///     hf_model_name: TheBloke/Mistral-7B-Instruct-v0.2-GGUF
+///     hf_file_name: mistral-7b-instruct-v0.2.Q4_K_M.gguf
+///     llamafile_output: mistral.llamafile
+///     image_name: registry.example.com/mistral:q4
+///     push: true
+///     args: ["--ctx-size", "8192"]
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Spec {
+    /// Number of entries built concurrently.
+    #[serde(default = "default_jobs")]
+    pub jobs: usize,
+    pub models: Vec<Entry>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    pub name: String,
+    pub hf_model_name: Option<String>,
+    pub hf_file_name: Option<String>,
+    pub hf_revision: Option<String>,
+    pub file_url: Option<String>,
+    pub file_path: Option<String>,
+    pub manifest_url: Option<String>,
+    /// Builds a llamafile to this path.
+    pub llamafile_output: Option<String>,
+    /// Builds a docker image with this name.
+    pub image_name: Option<String>,
+    /// Pushes the docker image after building it.
+    #[serde(default)]
+    pub push: bool,
+    /// Any other command line arguments of the launcher.
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+fn default_jobs() -> usize {
+    1
+}
+
+impl Spec {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let spec: Self = serde_yaml::from_str(&contents)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        if spec.jobs == 0 {
+            anyhow::bail!("jobs must be at least 1");
+        }
+        for entry in &spec.models {
+            if entry.push && entry.image_name.is_none() {
+                anyhow::bail!("'{}' sets push without an image_name", entry.name);
+            }
+        }
+
+        Ok(spec)
+    }
+}
+
+impl Entry {
+    /// Command line of the launcher building this entry.
+    pub fn argv(&self) -> Vec<String> {
+        let mut argv = vec!["launcher".to_string()];
+
+        let options = [
+            ("--hf-model-name", &self.hf_model_name),
+            ("--hf-file-name", &self.hf_file_name),
+            ("--hf-revision", &self.hf_revision),
+            ("--file-url", &self.file_url),
+            ("--file-path", &self.file_path),
+            ("--manifest-url", &self.manifest_url),
+            ("--llamafile-output", &self.llamafile_output),
+            ("--image-name", &self.image_name),
+        ];
+        for (flag, value) in options {
+            if let Some(value) = value {
+                argv.extend([flag.to_string(), value.clone()]);
+            }
+        }
+
+        if self.llamafile_output.is_some() {
+            argv.push("--build-llamafile".to_string());
+        }
+        if self.image_name.is_some() {
+            argv.push("--docker-build".to_string());
+        }
+
+        argv.extend(self.args.iter().cloned());
+        argv
+    }
+}
@@ -0,0 +1,149 @@
+use anyhow::{Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    completion::CompletionRequest,
+    http_client::HttpClient,
+    runner::{self, Runner},
+};
+
+const BENCH_PROMPT: &str =
+    "Write a short story about a lighthouse keeper who discovers a message in a bottle.";
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum OutputFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct BenchArgs {
+    #[arg(help = "Local model file path")]
+    model: String,
+
+    #[arg(long, default_value_t = 128, help = "Number of tokens to generate")]
+    tokens: u32,
+
+    #[arg(short, long, help = "Number of threads used by the server")]
+    threads: Option<u32>,
+
+    #[arg(long = "ngl", help = "Number of layers to offload to the GPU")]
+    n_gpu_layers: Option<u32>,
+
+    #[arg(long, help = "Port the server listens on, a free one by default")]
+    port: Option<u16>,
+
+    #[arg(
+        long,
+        default_value_t = 300,
+        help = "Seconds to wait for the model to load"
+    )]
+    load_timeout: u64,
+
+    #[arg(long, value_enum, default_value_t, help = "Output format")]
+    output: OutputFormat,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BenchResult {
+    pub load_seconds: f64,
+    pub prompt_tokens: u64,
+    pub prompt_tokens_per_second: f64,
+    pub generated_tokens: u64,
+    pub generation_tokens_per_second: f64,
+}
+
+#[derive(Deserialize)]
+struct CompletionResponse {
+    timings: Timings,
+}
+
+#[derive(Deserialize)]
+struct Timings {
+    prompt_n: u64,
+    prompt_per_second: f64,
+    predicted_n: u64,
+    predicted_per_second: f64,
+}
+
+pub async fn bench(runner: &Runner, args: &BenchArgs) -> Result<()> {
+    let model_path = Path::new(&args.model);
+    if !model_path.exists() {
+        anyhow::bail!("File path '{}' does not exist", model_path.display());
+    }
+
+    let port = match args.port {
+        Some(port) => {
+            runner::check_port_free(port)?;
+            port
+        }
+        None => runner::free_port()?,
+    };
+    let mut command = runner.local_command(model_path, port);
+    if let Some(threads) = args.threads {
+        command.arg("-t").arg(threads.to_string());
+    }
+    if let Some(n_gpu_layers) = args.n_gpu_layers {
+        command.arg("-ngl").arg(n_gpu_layers.to_string());
+    }
+
+    info!("Starting server..");
+    let started = Instant::now();
+    let mut server = command.spawn().context("Failed to start server")?;
+
+    let base_url = format!("http://127.0.0.1:{}", port);
+    runner::wait_started(
+        &mut server,
+        &base_url,
+        Duration::from_secs(args.load_timeout),
+    )
+    .await
+    .context("Server failed to start")?;
+    let load_seconds = started.elapsed().as_secs_f64();
+    info!("Model loaded in {:.2}s", load_seconds);
+
+    info!("Generating {} tokens..", args.tokens);
+    let response: CompletionResponse = HttpClient::new()
+        .post(
+            &format!("{}/completion", base_url),
+            &CompletionRequest {
+                prompt: BENCH_PROMPT,
+                n_predict: args.tokens,
+                stream: false,
+            },
+        )
+        .await?;
+
+    server.kill().await?;
+
+    let result = BenchResult {
+        load_seconds,
+        prompt_tokens: response.timings.prompt_n,
+        prompt_tokens_per_second: response.timings.prompt_per_second,
+        generated_tokens: response.timings.predicted_n,
+        generation_tokens_per_second: response.timings.predicted_per_second,
+    };
+
+    match args.output {
+        OutputFormat::Human => {
+            println!("Load time:          {:.2}s", result.load_seconds);
+            println!(
+                "Prompt evaluation:  {:.2} tokens/s ({} tokens)",
+                result.prompt_tokens_per_second, result.prompt_tokens
+            );
+            println!(
+                "Generation:         {:.2} tokens/s ({} tokens)",
+                result.generation_tokens_per_second, result.generated_tokens
+            );
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&result)?),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::{fs::File, io::Read, path::Path};
+
+use crate::http_client;
+
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)
+        .with_context(|| format!("Failed to open '{}' for hashing", path.display()))?;
+    let size = file.metadata()?.len();
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+
+    let pb = http_client::progress_bar(size, 0);
+    pb.set_message(format!("Hashing {}", path.display()));
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        pb.inc(read as u64);
+    }
+
+    pb.finish_and_clear();
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Verifies `path` against `expected`, or only warns when no checksum is known.
+pub fn verify_sha256(path: &Path, expected: Option<&str>) -> Result<()> {
+    let Some(expected) = expected else {
+        warn!(
+            "No checksum available for '{}', skipping verification",
+            path.display()
+        );
+        return Ok(());
+    };
+
+    info!("Verifying checksum of {}..", path.display());
+    let actual = sha256_file(path)?;
+    if !actual.eq_ignore_ascii_case(expected) {
+        anyhow::bail!(
+            "Checksum mismatch for '{}': expected {}, got {}",
+            path.display(),
+            expected,
+            actual
+        );
+    }
+
+    info!("Checksum of {} verified", path.display());
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{io::Write, path::Path, time::Duration};
+
+use crate::{
+    http_client::HttpClient,
+    runner::{self, Runner},
+};
+
+const LOAD_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Serialize)]
+pub struct CompletionRequest<'a> {
+    pub prompt: &'a str,
+    pub n_predict: u32,
+    pub stream: bool,
+}
+
+#[derive(Deserialize)]
+struct CompletionResponse {
+    content: String,
+}
+
+/// Starts the server on a free loopback port, prints its completion of `prompt` to stdout, then
+/// stops it.
+pub async fn complete(
+    runner: &Runner,
+    model_path: &Path,
+    prompt: &str,
+    tokens: u32,
+    stream: bool,
+) -> Result<()> {
+    let port = runner::free_port()?;
+    info!("Starting server..");
+    let mut server = runner
+        .local_command(model_path, port)
+        .spawn()
+        .context("Failed to start server")?;
+
+    let base_url = format!("http://127.0.0.1:{}", port);
+    runner::wait_started(&mut server, &base_url, LOAD_TIMEOUT)
+        .await
+        .context("Server failed to start")?;
+
+    let url = format!("{}/completion", base_url);
+    let request = CompletionRequest {
+        prompt,
+        n_predict: tokens,
+        stream,
+    };
+    let mut http_client = HttpClient::new();
+
+    if stream {
+        let mut events = http_client.post_stream(&url, &request).await?;
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut stdout = std::io::stdout();
+
+        // The server sends server-sent events, one `data: <json>` line per token.
+        while let Some(chunk) = events.next().await {
+            buffer.extend_from_slice(&chunk.context("Error while reading completion")?);
+
+            while let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=pos).collect();
+                let line = String::from_utf8_lossy(&line);
+                if let Some(data) = line.trim().strip_prefix("data: ") {
+                    let event: CompletionResponse =
+                        serde_json::from_str(data).context("Failed to parse completion event")?;
+                    print!("{}", event.content);
+                    stdout.flush()?;
+                }
+            }
+        }
+        println!();
+    } else {
+        let response: CompletionResponse = http_client.post(&url, &request).await?;
+        println!("{}", response.content);
+    }
+
+    server.kill().await?;
+
+    Ok(())
+}
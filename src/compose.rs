@@ -0,0 +1,106 @@
+use anyhow::{Context, Result};
+use log::info;
+use serde::Serialize;
+use std::{collections::BTreeMap, path::Path};
+
+use crate::output;
+
+#[derive(Serialize)]
+struct Compose {
+    services: BTreeMap<String, Service>,
+}
+
+#[derive(Serialize)]
+struct Service {
+    image: String,
+    ports: Vec<String>,
+    restart: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deploy: Option<Deploy>,
+}
+
+#[derive(Serialize)]
+struct Deploy {
+    resources: Resources,
+}
+
+#[derive(Serialize)]
+struct Resources {
+    reservations: Reservations,
+}
+
+#[derive(Serialize)]
+struct Reservations {
+    devices: Vec<Device>,
+}
+
+#[derive(Serialize)]
+struct Device {
+    driver: String,
+    count: String,
+    capabilities: Vec<String>,
+}
+
+/// Writes a Compose file running `image_name`, with its server's `port` published on the
+/// same port of the host. `gpu` reserves all the NVIDIA GPUs of the host for the service.
+pub fn write(path: &Path, image_name: &str, port: u16, gpu: bool) -> Result<()> {
+    let service_name = service_name(image_name);
+    let compose = Compose {
+        services: BTreeMap::from([(
+            service_name,
+            Service {
+                image: image_name.to_string(),
+                ports: vec![format!("{0}:{0}", port)],
+                restart: "unless-stopped".to_string(),
+                deploy: gpu.then(|| Deploy {
+                    resources: Resources {
+                        reservations: Reservations {
+                            devices: vec![Device {
+                                driver: "nvidia".to_string(),
+                                count: "all".to_string(),
+                                capabilities: vec!["gpu".to_string()],
+                            }],
+                        },
+                    },
+                }),
+            },
+        )]),
+    };
+
+    let yaml = serde_yaml::to_string(&compose)?;
+    serde_yaml::from_str::<serde_yaml::Value>(&yaml).context("Generated invalid YAML")?;
+
+    output::check_writable(path)?;
+    std::fs::write(path, yaml).with_context(|| format!("Failed to write {}", path.display()))?;
+    info!("Wrote Compose file to {}", path.display());
+
+    Ok(())
+}
+
+/// Compose service names are limited to lowercase letters, digits, `-` and `_`.
+fn service_name(image_name: &str) -> String {
+    let name = image_name
+        .rsplit('/')
+        .next()
+        .unwrap_or(image_name)
+        .split(':')
+        .next()
+        .unwrap_or(image_name);
+
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+
+    if name.is_empty() {
+        "llamafile".to_string()
+    } else {
+        name
+    }
+}
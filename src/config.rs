@@ -0,0 +1,220 @@
+use anyhow::{Context, Result};
+use clap::{ArgAction, ArgMatches};
+use serde::Deserialize;
+use std::{any::TypeId, fmt::Write as _, path::PathBuf};
+
+use crate::{
+    http_client::{HeaderRule, Secret},
+    runner,
+};
+
+/// Settings read from a TOML file: defaults for the command line arguments, and those that don't
+/// fit on the command line.
+///
+/// Arguments are resolved in this order, the first one set winning:
+///
+/// 1. flags on the command line,
+/// 2. environment variables,
+/// 3. the config file,
+/// 4. built-in defaults.
+#[derive(Deserialize, Debug, Default)]
+pub struct ConfigFile {
+    /// Headers to send to matching URLs:
+    ///
+    /// ```toml
+    /// [[headers]]
+    /// prefix = "https://artifacts.example.com/"
+    /// headers = { "X-Api-Key" = "..." }
+    /// ```
+    #[serde(default)]
+    pub headers: Vec<HeaderRule>,
+
+    /// Defaults of the top-level arguments, named as in the `--print-config` output:
+    ///
+    /// ```toml
+    /// model_dir = "/srv/models"
+    /// llamafile_server_path = "/opt/llamafile/llamafile-server"
+    /// docker_exclude = ["*.md"]
+    /// ```
+    #[serde(flatten)]
+    pub defaults: toml::Table,
+}
+
+impl ConfigFile {
+    /// Reads `path`, or the default config file if it exists.
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => match default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Makes the settings of the file the defaults of `command`'s arguments, which flags and
+    /// environment variables still override.
+    pub fn apply_defaults(&self, mut command: clap::Command) -> Result<clap::Command> {
+        for (key, value) in &self.defaults {
+            let multiple = match command
+                .get_arguments()
+                .find(|arg| arg.get_id() == key.as_str())
+            {
+                Some(_) if NOT_CONFIGURABLE.contains(&key.as_str()) => {
+                    anyhow::bail!("'{}' can't be set in the config file", key)
+                }
+                Some(arg) => matches!(arg.get_action(), ArgAction::Append),
+                None => anyhow::bail!("Unknown setting '{}'", key),
+            };
+
+            let values = match value {
+                toml::Value::Array(values) if multiple => values
+                    .iter()
+                    .map(|value| scalar(key, value))
+                    .collect::<Result<Vec<_>>>()?,
+                value => vec![scalar(key, value)?],
+            };
+            command = command.mut_arg(key, |arg| arg.default_values(values));
+        }
+        Ok(command)
+    }
+}
+
+/// Arguments only meaningful on the command line.
+const NOT_CONFIGURABLE: &[&str] = &["config", "print_config", "generate_completions"];
+
+fn scalar(key: &str, value: &toml::Value) -> Result<String> {
+    match value {
+        toml::Value::String(value) => Ok(value.clone()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        _ => anyhow::bail!("Invalid value for '{}': {}", key, value),
+    }
+}
+
+/// `$XDG_CONFIG_HOME/llamafile-launcher/config.toml`, defaulting to `~/.config`.
+fn default_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("llamafile-launcher").join("config.toml"))
+}
+
+/// Arguments left out of the resolved configuration, besides secrets: those only controlling
+/// printing it.
+const SKIPPED: &[&str] = &["print_config"];
+
+struct Resolved {
+    id: String,
+    long: String,
+    kind: Kind,
+    values: Vec<String>,
+}
+
+enum Kind {
+    Flag,
+    Single,
+    Multiple,
+}
+
+/// Prints the effective top-level arguments, resolved from flags, the environment and defaults,
+/// as a command line and as a TOML block.
+pub fn print(command: &clap::Command, matches: &ArgMatches) {
+    let resolved = resolve(command, matches);
+    println!("{}", command_line(command.get_name(), &resolved));
+    println!();
+    print!("{}", toml(&resolved));
+}
+
+fn resolve(command: &clap::Command, matches: &ArgMatches) -> Vec<Resolved> {
+    command
+        .get_arguments()
+        .filter_map(|arg| {
+            let id = arg.get_id().as_str();
+            let long = arg.get_long()?;
+            let secret = arg.get_value_parser().type_id() == TypeId::of::<Secret>();
+            if secret || SKIPPED.contains(&id) || matches.value_source(id).is_none() {
+                return None;
+            }
+
+            let values = matches
+                .get_raw(id)?
+                .map(|value| value.to_string_lossy().to_string())
+                .collect();
+            let kind = match arg.get_action() {
+                ArgAction::SetTrue => Kind::Flag,
+                ArgAction::Append => Kind::Multiple,
+                _ => Kind::Single,
+            };
+
+            Some(Resolved {
+                id: id.to_string(),
+                long: long.to_string(),
+                kind,
+                values,
+            })
+        })
+        .collect()
+}
+
+fn command_line(program: &str, resolved: &[Resolved]) -> String {
+    let mut line = program.to_string();
+    for arg in resolved {
+        match arg.kind {
+            Kind::Flag => {
+                if arg.values.iter().any(|value| value == "true") {
+                    line.push_str(&format!(" --{}", arg.long));
+                }
+            }
+            Kind::Single | Kind::Multiple => {
+                for value in &arg.values {
+                    line.push_str(&format!(" --{} {}", arg.long, runner::shell_quote(value)));
+                }
+            }
+        }
+    }
+    line
+}
+
+fn toml(resolved: &[Resolved]) -> String {
+    let mut toml = String::new();
+    for arg in resolved {
+        let value = match arg.kind {
+            Kind::Flag => arg.values.iter().any(|value| value == "true").to_string(),
+            Kind::Single => arg
+                .values
+                .first()
+                .map(|v| toml_value(v))
+                .unwrap_or_default(),
+            Kind::Multiple => format!(
+                "[{}]",
+                arg.values
+                    .iter()
+                    .map(|value| toml_string(value))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        let _ = writeln!(toml, "{} = {}", arg.id, value);
+    }
+    toml
+}
+
+fn toml_value(value: &str) -> String {
+    if value.parse::<i64>().is_ok() {
+        value.to_string()
+    } else {
+        toml_string(value)
+    }
+}
+
+/// JSON string escapes are a subset of TOML basic string escapes.
+fn toml_string(value: &str) -> String {
+    serde_json::Value::from(value).to_string()
+}
@@ -1,18 +1,208 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use flate2::{write::GzEncoder, Compression};
 use futures_util::StreamExt;
-use log::{debug, error, info};
-use std::path::Path;
-use tar::Header;
+use log::{debug, info, warn};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+use tar::{EntryType, Header};
 
-pub(crate) struct Docker {
+use crate::{llamafile_builder, models, runner};
+
+/// Patterns always excluded from the build context: partial downloads and hidden files.
+const DEFAULT_EXCLUDES: &[&str] = &["*.part", ".*"];
+
+/// Size of the chunks the build context is streamed to the daemon in.
+const CONTEXT_CHUNK_SIZE: usize = 1024 * 1024;
+/// Chunks written ahead of the daemon reading them, bounding the memory used by the context.
+const CONTEXT_CHUNKS_AHEAD: usize = 8;
+
+/// Base image of generated Dockerfiles, unless another is given.
+pub const DEFAULT_BASE_IMAGE: &str = "debian:bullseye-slim";
+/// Base image of GPU-enabled images, providing the CUDA runtime.
+pub const DEFAULT_GPU_BASE_IMAGE: &str = "nvidia/cuda:12.2.2-runtime-ubuntu22.04";
+/// Gzip level of the build context. Models barely compress, so the fastest level only saves
+/// a little, but still helps with remote daemons.
+pub const DEFAULT_COMPRESSION: u32 = 1;
+/// User and group the server runs as, unless others are given.
+pub const DEFAULT_UID: u32 = 1000;
+pub const DEFAULT_GID: u32 = 1000;
+/// Offloads all the layers of any model.
+pub const DEFAULT_GPU_LAYERS: u32 = 999;
+
+pub struct Docker {
     docker: bollard::Docker,
+    excludes: Vec<String>,
+    init: bool,
+    server_args: Vec<String>,
+    base_image: String,
+    compression: u32,
+    port: u16,
+    uid: u32,
+    gid: u32,
+    /// Layers offloaded to the GPU, for GPU-enabled images.
+    gpu_layers: Option<u32>,
+    default_model_index: usize,
+    /// Multimodal projector of the served model.
+    projector: Option<PathBuf>,
+    /// Dockerfile used instead of the generated one, see [`Docker::set_dockerfile_template`].
+    dockerfile_template: Option<String>,
 }
 
 impl Docker {
-    pub fn new() -> Result<Self> {
+    /// `init` runs the server under `tini`, so that it receives signals such as `docker stop`'s
+    /// SIGTERM instead of being killed after the grace period.
+    pub fn new(excludes: Vec<String>, init: bool) -> Result<Self> {
         let docker = bollard::Docker::connect_with_local_defaults()?;
-        Ok(Self { docker })
+        let excludes = DEFAULT_EXCLUDES
+            .iter()
+            .map(ToString::to_string)
+            .chain(excludes)
+            .collect();
+        Ok(Self {
+            docker,
+            excludes,
+            init,
+            server_args: Vec::new(),
+            base_image: DEFAULT_BASE_IMAGE.to_string(),
+            compression: DEFAULT_COMPRESSION,
+            port: llamafile_builder::DEFAULT_PORT,
+            uid: DEFAULT_UID,
+            gid: DEFAULT_GID,
+            gpu_layers: None,
+            default_model_index: 0,
+            projector: None,
+            dockerfile_template: None,
+        })
+    }
+
+    /// Builds `FROM` `base_image` rather than [`DEFAULT_BASE_IMAGE`], such as an `nvidia/cuda`
+    /// image for GPU inference. The image must provide `apt-get` and `adduser`, as Debian and
+    /// Ubuntu based images do.
+    pub fn set_base_image(&mut self, base_image: String) {
+        self.base_image = base_image;
+    }
+
+    /// Base image of the generated Dockerfile, by tag unless it was pinned.
+    pub fn base_image(&self) -> &str {
+        &self.base_image
+    }
+
+    /// Resolves the base image's tag to its immutable digest, pulling the image if it isn't
+    /// available locally, and builds `FROM` that digest. Returns the pinned reference.
+    pub async fn pin_base_image(&mut self) -> Result<String> {
+        let base_image = self.base_image.clone();
+        if base_image.contains('@') {
+            return Ok(base_image);
+        }
+        let (repository, tag) = split_tag(&base_image);
+
+        if self.docker.inspect_image(&base_image).await.is_err() {
+            info!("Pulling {} to resolve its digest", base_image);
+            let options = bollard::image::CreateImageOptions {
+                from_image: repository,
+                tag,
+                ..Default::default()
+            };
+            let mut pull = self.docker.create_image(Some(options), None, None);
+            while let Some(msg) = pull.next().await {
+                msg.with_context(|| {
+                    format!("Failed to pull {}, is the registry reachable?", base_image)
+                })?;
+            }
+        }
+
+        let inspect = self
+            .docker
+            .inspect_image(&base_image)
+            .await
+            .with_context(|| format!("Failed to inspect {}", base_image))?;
+        let prefix = format!("{}@", repository);
+        let pinned = inspect
+            .repo_digests
+            .unwrap_or_default()
+            .into_iter()
+            .find(|digest| digest.starts_with(&prefix))
+            .with_context(|| format!("{} has no registry digest", base_image))?;
+
+        info!("Pinned {} to {}", base_image, pinned);
+        self.base_image = pinned.clone();
+        Ok(pinned)
+    }
+
+    /// Gzip level of the build context, from 0 (none) to 9 (smallest).
+    pub fn set_compression(&mut self, level: u32) {
+        self.compression = level;
+    }
+
+    /// Port the server listens on and the image exposes. The server always listens on all
+    /// interfaces, as the container is only reachable through published ports.
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+
+    /// Runs the server as a non-root user with these IDs, rather than [`DEFAULT_UID`] and
+    /// [`DEFAULT_GID`], which may be taken in the base image.
+    pub fn set_user(&mut self, uid: u32, gid: u32) -> Result<()> {
+        if uid == 0 || gid == 0 {
+            anyhow::bail!(
+                "The server can't run as root, got UID {} and GID {}",
+                uid,
+                gid
+            );
+        }
+        self.uid = uid;
+        self.gid = gid;
+        Ok(())
+    }
+
+    /// Offloads `layers` layers to the GPU, building on [`DEFAULT_GPU_BASE_IMAGE`] unless another
+    /// base image was set. The image then has to be run with `--gpus all`, on a host with the
+    /// NVIDIA Container Toolkit.
+    pub fn set_gpu_layers(&mut self, layers: u32) {
+        if self.base_image == DEFAULT_BASE_IMAGE {
+            self.base_image = DEFAULT_GPU_BASE_IMAGE.to_string();
+        }
+        self.gpu_layers = Some(layers);
+    }
+
+    /// Selects which of the models copied into the image is served, as `model-<index>`.
+    pub fn set_default_model_index(&mut self, index: usize) {
+        self.default_model_index = index;
+    }
+
+    /// Copies a multimodal projector into the image, passing it to the server with `--mmproj`.
+    pub fn set_projector(&mut self, projector: Option<PathBuf>) {
+        self.projector = projector;
+    }
+
+    /// Builds from `template` rather than the generated Dockerfile. The build context holds the
+    /// same files either way, and these placeholders of the template are substituted:
+    ///
+    /// - `{{LLAMAFILE}}`: llamafile-server,
+    /// - `{{MODEL}}`: the served model, and `{{MODEL_0}}`, `{{MODEL_1}}`, ... each model,
+    /// - `{{MMPROJ}}`: the multimodal projector, if there is one,
+    /// - `{{BASE_IMAGE}}`: the base image, pinned or not,
+    /// - `{{PORT}}`: the port the server should listen on,
+    /// - `{{UID}}` and `{{GID}}`: the user and group the server should run as.
+    ///
+    /// ```dockerfile
+    /// FROM {{BASE_IMAGE}}
+    /// RUN apt-get update && apt-get install -y --no-install-recommends ca-certificates
+    /// WORKDIR /app
+    /// COPY /{{LLAMAFILE}} /{{MODEL}} ./
+    /// EXPOSE {{PORT}}
+    /// ENTRYPOINT ["/bin/sh", "./{{LLAMAFILE}}", "-m", "{{MODEL}}", "--host", "0.0.0.0", "--port", "{{PORT}}"]
+    /// ```
+    pub fn set_dockerfile_template(&mut self, template: Option<String>) {
+        self.dockerfile_template = template;
+    }
+
+    /// Arguments added to the server's entrypoint.
+    pub fn set_server_args(&mut self, server_args: Vec<String>) {
+        self.server_args = server_args;
     }
 
     pub async fn build_image(
@@ -22,10 +212,39 @@ impl Docker {
         llama_path: &Path,
     ) -> Result<()> {
         info!("Building image: {}", image_name);
-        let dockerfile = self.dockerfile(&model_path);
+        let Some(&default_model) = model_path.get(self.default_model_index) else {
+            anyhow::bail!(
+                "Default model index {} is out of range, only {} models are given",
+                self.default_model_index,
+                model_path.len()
+            );
+        };
+        if self.is_excluded(default_model) {
+            anyhow::bail!(
+                "The default model {} is excluded from the build context",
+                default_model.display()
+            );
+        }
+        let model_path: Vec<&Path> = model_path
+            .into_iter()
+            .filter(|path| !self.is_excluded(path))
+            .collect();
+        // Excluded models shift the `model-<index>` names of the models after them.
+        let default_index = model_path
+            .iter()
+            .position(|path| *path == default_model)
+            .unwrap_or_default();
+
+        let dockerfile = match self.dockerfile_template.as_ref() {
+            Some(template) => self.render_dockerfile(template, &model_path, default_index)?,
+            None => self.dockerfile(
+                &model_path,
+                default_index,
+                llamafile_builder::needs_server_flag(llama_path),
+            ),
+        };
         debug!("Dockerfile: {}", dockerfile);
-        info!("Building tarball.. This may take a while.");
-        let tarball = self.tarball(dockerfile, model_path, llama_path)?;
+        let (tarball, tarball_writer) = self.tarball(dockerfile, model_path, llama_path);
 
         let image_options = bollard::image::BuildImageOptions {
             dockerfile: "Dockerfile",
@@ -35,79 +254,480 @@ impl Docker {
         };
 
         info!("Building image.. This may take a while.");
-        let mut build_image = self
-            .docker
-            .build_image(image_options, None, Some(tarball.into()));
+        let mut build_image = self.docker.build_image(image_options, None, Some(tarball));
 
+        let mut build_error = None;
+        let mut stream_error = None;
         while let Some(msg) = build_image.next().await {
-            if let Ok(msg) = msg {
-                info!("{:?}", msg);
-            } else {
-                error!("{:?}", msg);
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(e) => {
+                    stream_error = Some(e);
+                    break;
+                }
+            };
+            if let Some(stream) = msg.stream.as_deref() {
+                for line in stream.lines().filter(|line| !line.trim().is_empty()) {
+                    info!("{}", line);
+                }
+            }
+            if let Some(status) = msg.status.as_deref() {
+                debug!("{}", status);
+            }
+            if let Some(error) = msg.error {
+                build_error = Some(
+                    msg.error_detail
+                        .and_then(|detail| detail.message)
+                        .unwrap_or(error),
+                );
+                break;
             }
         }
+        drop(build_image);
 
+        // The context stops being read when the build fails, so the daemon's error comes first,
+        // but a context that failed to be written explains a broken connection better.
+        let written = tarball_writer.await?;
+        if let Some(error) = build_error {
+            anyhow::bail!("Failed to build {}: {}", image_name, error.trim());
+        }
+        written.context("Failed to write the build context")?;
+        if let Some(e) = stream_error {
+            return Err(e).with_context(|| format!("Failed to build {}", image_name));
+        }
         Ok(())
     }
 
-    fn dockerfile(&self, models_path: &[&Path]) -> String {
-        let mut dockerfile = String::from(
+    /// Id of the local image `image_name`.
+    pub async fn image_id(&self, image_name: &str) -> Result<String> {
+        self.docker
+            .inspect_image(image_name)
+            .await
+            .with_context(|| format!("Failed to inspect {}", image_name))?
+            .id
+            .with_context(|| format!("{} has no id", image_name))
+    }
+
+    /// Pushes `image_name` to its registry, with `credentials` if the registry requires a login.
+    pub async fn push_image(
+        &self,
+        image_name: &str,
+        credentials: Option<bollard::auth::DockerCredentials>,
+    ) -> Result<()> {
+        let (repository, tag) = split_tag(image_name);
+        info!("Pushing image: {}", image_name);
+
+        let options = bollard::image::PushImageOptions { tag };
+        let mut push = self
+            .docker
+            .push_image(repository, Some(options), credentials);
+        while let Some(msg) = push.next().await {
+            let msg = msg.with_context(|| format!("Failed to push {}", image_name))?;
+            if let Some(error) = msg.error {
+                anyhow::bail!("Failed to push {}: {}", image_name, error);
+            }
+            debug!("{:?}", msg);
+        }
+
+        info!("Pushed image: {}", image_name);
+        Ok(())
+    }
+
+    /// Docker CLI commands equivalent to building the image from its context, then running it.
+    pub fn equivalent_commands(&self, image_name: &str) -> [String; 2] {
+        let image_name = runner::shell_quote(image_name);
+        [
+            format!(
+                "docker build -t {} -  # context: Dockerfile, llamafile-server, model-*",
+                image_name
+            ),
+            format!(
+                "docker run --rm {}-p {1}:{1} {2}",
+                if self.gpu_layers.is_some() {
+                    "--gpus all "
+                } else {
+                    ""
+                },
+                self.port,
+                image_name
+            ),
+        ]
+    }
+
+    fn dockerfile(&self, models_path: &[&Path], default_index: usize, server_flag: bool) -> String {
+        let mut dockerfile = format!(
             r#"
-FROM debian:bullseye-slim AS final
-RUN addgroup --gid 1000 user
-RUN adduser --uid 1000 --gid 1000 --disabled-password --gecos "" user
+FROM {} AS final
+"#,
+            self.base_image
+        );
+
+        if self.init {
+            dockerfile.push_str(
+                r#"RUN apt-get update \
+    && apt-get install -y --no-install-recommends tini \
+    && rm -rf /var/lib/apt/lists/*
+"#,
+            );
+        }
+
+        dockerfile.push_str(&format!(
+            r#"RUN addgroup --gid {1} user
+RUN adduser --uid {0} --gid {1} --disabled-password --gecos "" user
 USER user
 WORKDIR /usr/src/app
 COPY /llamafile-server ./llamafile-server
 "#,
-        );
+            self.uid, self.gid
+        ));
 
-        for (i, _) in models_path.iter().enumerate() {
-            dockerfile.push_str(&format!("COPY /model-{} ./model-{}\n", i, i));
+        for (i, model_path) in models_path.iter().enumerate() {
+            let name = image_model_name(i, model_path);
+            dockerfile.push_str(&format!("COPY /{0} ./{0}\n", name));
+        }
+        if self.projector.is_some() {
+            dockerfile.push_str("COPY /mmproj ./mmproj\n");
         }
 
-        dockerfile.push_str(
+        dockerfile.push_str(&format!(
             r#"
-# Expose 8080 port.
-EXPOSE 8080
+# Expose {0} port.
+EXPOSE {0}
 
 # Set entrypoint.
-ENTRYPOINT ["/bin/sh", "/usr/src/app/llamafile-server", "-m", "/usr/src/app/model-0", "--host", "0.0.0.0"]
 "#,
-        );
+            self.port
+        ));
+
+        let init = if self.init {
+            r#""/usr/bin/tini", "--", "#
+        } else {
+            ""
+        };
+        let server_flag = if server_flag { r#""--server", "# } else { "" };
+        let mut entrypoint_args = Vec::new();
+        if self.projector.is_some() {
+            entrypoint_args.extend(["--mmproj".to_string(), "/usr/src/app/mmproj".to_string()]);
+        }
+        if let Some(layers) = self.gpu_layers {
+            entrypoint_args.extend(["-ngl".to_string(), layers.to_string()]);
+        }
+        let server_args: String = entrypoint_args
+            .iter()
+            .chain(&self.server_args)
+            .map(|arg| format!(", {}", serde_json::Value::from(arg.as_str())))
+            .collect();
+        dockerfile.push_str(&format!(
+            r#"ENTRYPOINT [{}"/bin/sh", "/usr/src/app/llamafile-server", {}"-m", "/usr/src/app/{}", "--host", "0.0.0.0", "--port", "{}"{}]
+"#,
+            init,
+            server_flag,
+            image_model_name(default_index, models_path[default_index]),
+            self.port,
+            server_args
+        ));
 
         dockerfile
     }
 
+    /// Substitutes the placeholders of a Dockerfile template, failing on unknown ones.
+    fn render_dockerfile(
+        &self,
+        template: &str,
+        models_path: &[&Path],
+        default_index: usize,
+    ) -> Result<String> {
+        let mut values = vec![
+            ("LLAMAFILE".to_string(), "llamafile-server".to_string()),
+            (
+                "MODEL".to_string(),
+                image_model_name(default_index, models_path[default_index]),
+            ),
+            ("BASE_IMAGE".to_string(), self.base_image.clone()),
+            ("PORT".to_string(), self.port.to_string()),
+            ("UID".to_string(), self.uid.to_string()),
+            ("GID".to_string(), self.gid.to_string()),
+        ];
+        for (i, model_path) in models_path.iter().enumerate() {
+            values.push((format!("MODEL_{}", i), image_model_name(i, model_path)));
+        }
+        if self.projector.is_some() {
+            values.push(("MMPROJ".to_string(), "mmproj".to_string()));
+        }
+
+        let mut dockerfile = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            let end = rest[start..]
+                .find("}}")
+                .context("Unterminated placeholder in the Dockerfile template")?;
+            let name = rest[start + 2..start + end].trim();
+            let value = values
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value)
+                .with_context(|| {
+                    format!(
+                        "Unknown placeholder {{{{{}}}}} in the Dockerfile template",
+                        name
+                    )
+                })?;
+
+            dockerfile.push_str(&rest[..start]);
+            dockerfile.push_str(value);
+            rest = &rest[start + end + 2..];
+        }
+        dockerfile.push_str(rest);
+        Ok(dockerfile)
+    }
+
+    /// Streams the build context while the daemon reads it, from a blocking task, so that
+    /// memory stays bounded whatever the size of the models. The task's result tells whether
+    /// the whole context was written.
     fn tarball(
         &self,
         dockerfile: String,
         models_path: Vec<&Path>,
         llama_path: &Path,
-    ) -> Result<Vec<u8>> {
-        let enc = GzEncoder::new(Vec::new(), Compression::new(0));
+    ) -> (hyper::Body, tokio::task::JoinHandle<Result<()>>) {
+        let mut files = vec![(
+            "./llamafile-server".to_string(),
+            llama_path.to_path_buf(),
+            0o755,
+        )];
+        for (i, model_path) in models_path.iter().enumerate() {
+            let name = format!("./{}", image_model_name(i, model_path));
+            files.push((name, model_path.to_path_buf(), 0o644));
+        }
+        if let Some(projector) = self.projector.as_ref() {
+            files.push(("./mmproj".to_string(), projector.clone(), 0o644));
+        }
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(CONTEXT_CHUNKS_AHEAD);
+        let compression = Compression::new(self.compression);
+        let writer = tokio::task::spawn_blocking(move || {
+            let written = write_tarball(
+                ChannelWriter(sender.clone()),
+                compression,
+                &dockerfile,
+                &files,
+            );
+            // Fails the request rather than sending a truncated context.
+            if let Err(e) = &written {
+                let _ = sender.blocking_send(Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("{:#}", e),
+                )));
+            }
+            written
+        });
+
+        let chunks = futures_util::stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|chunk| (chunk, receiver))
+        });
+        (hyper::Body::wrap_stream(chunks), writer)
+    }
 
-        let mut tarball = tar::Builder::new(enc);
+    fn is_excluded(&self, path: &Path) -> bool {
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let full_path = path.to_string_lossy();
 
-        debug!("Appending llamafile-server..");
-        tarball.append_path_with_name(llama_path, "./llamafile-server")?;
+        // Patterns containing a separator match the whole path, others only the file name.
+        let pattern = self.excludes.iter().find(|pattern| {
+            let candidate = if pattern.contains('/') {
+                &full_path
+            } else {
+                &file_name
+            };
+            glob_match(pattern.as_bytes(), candidate.as_bytes())
+        });
 
-        debug!("Appending Dockerfile..");
-        let mut header = Header::new_gnu();
-        header.set_path("./Dockerfile")?;
-        header.set_size(dockerfile.len() as u64);
-        header.set_mode(0o755);
-        header.set_cksum();
+        if let Some(pattern) = pattern {
+            warn!(
+                "Excluding {} from the build context (matches '{}')",
+                path.display(),
+                pattern
+            );
+        }
 
-        tarball.append_data(&mut header, "./Dockerfile", dockerfile.as_bytes())?;
+        pattern.is_some()
+    }
+}
 
-        for (i, model_path) in models_path.iter().enumerate() {
-            debug!("Appending model-{} from {}..", i, model_path.display());
-            tarball.append_path_with_name(model_path, &format!("./model-{}", i))?;
+/// Splits `image_name` into its repository and tag, which defaults to `latest`. A `:` before
+/// the last `/` belongs to the registry's port.
+fn split_tag(image_name: &str) -> (&str, &str) {
+    match image_name.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => (repository, tag),
+        _ => (image_name, "latest"),
+    }
+}
+
+/// Writes the tar stream of the build context, gzipped, into `writer`.
+fn write_tarball<W: Write>(
+    writer: W,
+    compression: Compression,
+    dockerfile: &str,
+    files: &[(String, PathBuf, u32)],
+) -> Result<()> {
+    let enc = GzEncoder::new(
+        BufWriter::with_capacity(CONTEXT_CHUNK_SIZE, writer),
+        compression,
+    );
+    let mut tarball = tar::Builder::new(enc);
+    let mtime = source_date_epoch();
+
+    debug!("Appending Dockerfile..");
+    let mut header = file_header(dockerfile.len() as u64, 0o755, mtime)?;
+    tarball.append_data(&mut header, "./Dockerfile", dockerfile.as_bytes())?;
+
+    for (name, path, mode) in files {
+        debug!("Appending {} from {}..", name, path.display());
+        append_file(&mut tarball, path, name, *mode, mtime)?;
+    }
+
+    tarball.into_inner()?.finish()?.flush()?;
+    Ok(())
+}
+
+/// Appends a file with normalized metadata, so that identical inputs always produce
+/// identical build contexts.
+fn append_file<W: Write>(
+    tarball: &mut tar::Builder<W>,
+    path: &Path,
+    name: &str,
+    mode: u32,
+    mtime: u64,
+) -> Result<()> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut header = file_header(size, mode, mtime)
+        .with_context(|| format!("Failed to append {}", path.display()))?;
+
+    tarball.append_data(&mut header, name, file)?;
+    Ok(())
+}
+
+/// Header of a regular file owned by root, with nothing taken from the file system.
+fn file_header(size: u64, mode: u32, mtime: u64) -> Result<Header> {
+    // GNU headers encode sizes above the 8GiB ustar limit in base-256, which the docker
+    // daemon understands. Check the encoding anyway, as a truncated size would silently
+    // corrupt the model in the image.
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(mode);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(mtime);
+    if header.size()? != size {
+        anyhow::bail!("Failed to encode a size of {} bytes in a tar header", size);
+    }
+    Ok(header)
+}
+
+/// Sends what's written to it as chunks of the body of a request.
+struct ChannelWriter(tokio::sync::mpsc::Sender<std::io::Result<bytes::Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .blocking_send(Ok(bytes::Bytes::copy_from_slice(buf)))
+            .map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "The docker daemon stopped reading the build context",
+                )
+            })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Name of the `i`th model in the image. Shards keep their file name, whose
+/// `-00001-of-00003.gguf` suffix llama.cpp finds the other shards by, and which tells the shards
+/// of different models apart.
+fn image_model_name(i: usize, model_path: &Path) -> String {
+    match model_path.file_name().and_then(|name| name.to_str()) {
+        Some(name) if models::shard_suffix(name).is_some() => name.to_string(),
+        _ => format!("model-{}", i),
+    }
+}
+
+/// Matches `name` against a pattern supporting the `*` and `?` wildcards.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
         }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
 
-        let tarball = tarball.into_inner()?;
+/// Timestamp used for every entry of the build context, honoring `SOURCE_DATE_EPOCH`.
+fn source_date_epoch() -> u64 {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn tarball_is_reproducible() {
+        let dir = tempfile::tempdir().unwrap();
+        let model = dir.path().join("model.gguf");
+        std::fs::write(&model, b"GGUF model").unwrap();
+        let files = [("model-0".to_string(), model.clone(), 0o644)];
+        let build = || {
+            let mut context = Vec::new();
+            write_tarball(&mut context, Compression::new(1), "FROM scratch\n", &files).unwrap();
+            context
+        };
+
+        // Touching the model between builds mustn't change the context.
+        let digests: Vec<_> = [1_000, 2_000]
+            .into_iter()
+            .map(|secs| {
+                File::options()
+                    .write(true)
+                    .open(&model)
+                    .unwrap()
+                    .set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+                    .unwrap();
+                Sha256::digest(build())
+            })
+            .collect();
+        assert_eq!(digests[0], digests[1]);
+
+        std::env::set_var("SOURCE_DATE_EPOCH", "1700000000");
+        let context = build();
+        std::env::remove_var("SOURCE_DATE_EPOCH");
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(&context[..]));
+        for entry in archive.entries().unwrap() {
+            assert_eq!(entry.unwrap().header().mtime().unwrap(), 1_700_000_000);
+        }
+    }
 
-        Ok(tarball.finish()?)
+    #[test]
+    fn file_header_encodes_sizes_above_ustar_limit() {
+        let size = 8 * 1024 * 1024 * 1024 + 1;
+        let header = file_header(size, 0o644, 0).unwrap();
+        assert!(header.as_gnu().is_some());
+        assert_eq!(header.size().unwrap(), size);
     }
 }
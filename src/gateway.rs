@@ -0,0 +1,150 @@
+use anyhow::{Context, Result};
+use hyper::{
+    header::{AUTHORIZATION, CONNECTION, CONTENT_LENGTH, HOST, TRANSFER_ENCODING},
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use log::{debug, info, warn};
+use std::{convert::Infallible, net::SocketAddr, path::Path, sync::Arc};
+
+use crate::runner::Runner;
+
+/// Minimal reverse proxy checking a bearer token before forwarding requests to the server.
+struct Gateway {
+    client: reqwest::Client,
+    upstream: String,
+    authorization: String,
+}
+
+impl Gateway {
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let authorized = req
+            .headers()
+            .get(AUTHORIZATION)
+            .is_some_and(|value| constant_time_eq(value.as_bytes(), self.authorization.as_bytes()));
+        if !authorized {
+            debug!("Rejecting unauthorized request to {}", req.uri());
+            return status_response(StatusCode::UNAUTHORIZED);
+        }
+
+        match self.forward(req).await {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("Failed to forward request: {}", e);
+                status_response(StatusCode::BAD_GATEWAY)
+            }
+        }
+    }
+
+    async fn forward(&self, req: Request<Body>) -> Result<Response<Body>> {
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+        let url = format!("{}{}", self.upstream, path);
+        debug!("Forwarding {} {}", req.method(), url);
+
+        let mut headers = req.headers().clone();
+        for header in [
+            AUTHORIZATION,
+            HOST,
+            CONNECTION,
+            CONTENT_LENGTH,
+            TRANSFER_ENCODING,
+        ] {
+            headers.remove(header);
+        }
+
+        let upstream_res = self
+            .client
+            .request(req.method().clone(), url)
+            .headers(headers)
+            .body(reqwest::Body::wrap_stream(req.into_body()))
+            .send()
+            .await?;
+
+        let mut res = Response::builder().status(upstream_res.status());
+        for (name, value) in upstream_res.headers() {
+            if *name != CONNECTION && *name != TRANSFER_ENCODING {
+                res = res.header(name, value);
+            }
+        }
+
+        Ok(res.body(Body::wrap_stream(upstream_res.bytes_stream()))?)
+    }
+}
+
+/// Compares without returning early, so that the time taken doesn't reveal how much of the token
+/// was guessed right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = status;
+    res
+}
+
+async fn serve(listen: SocketAddr, upstream: String, token: &str) -> Result<()> {
+    let gateway = Arc::new(Gateway {
+        client: reqwest::Client::new(),
+        upstream,
+        authorization: format!("Bearer {}", token),
+    });
+
+    let make_service = make_service_fn(move |_| {
+        let gateway = gateway.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let gateway = gateway.clone();
+                async move { Ok::<_, Infallible>(gateway.handle(req).await) }
+            }))
+        }
+    });
+
+    info!("Gateway listening on {}", listen);
+    Server::try_bind(&listen)
+        .with_context(|| format!("Failed to bind gateway to {}", listen))?
+        .serve(make_service)
+        .await?;
+
+    Ok(())
+}
+
+/// Builds the command running the model on a loopback port, for the gateway to forward to.
+pub fn server_command(
+    runner: &Runner,
+    model_path: &Path,
+    upstream_port: u16,
+) -> tokio::process::Command {
+    let mut command = runner.command_at(model_path, "127.0.0.1", upstream_port);
+    command.kill_on_drop(true);
+    command
+}
+
+/// Runs the model on a loopback port, behind the gateway listening on `listen`.
+pub async fn run(
+    runner: &Runner,
+    model_path: &Path,
+    listen: SocketAddr,
+    upstream_port: u16,
+    token: &str,
+) -> Result<()> {
+    let mut server = server_command(runner, model_path, upstream_port)
+        .spawn()
+        .context("Failed to start server")?;
+    let upstream = format!("http://127.0.0.1:{}", upstream_port);
+
+    tokio::select! {
+        status = server.wait() => {
+            let status = status?;
+            if !status.success() {
+                anyhow::bail!("Server exited with {}", status);
+            }
+            Ok(())
+        }
+        res = serve(listen, upstream, token) => res,
+    }
+}
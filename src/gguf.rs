@@ -0,0 +1,259 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+pub const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+
+/// Scalar metadata values of a GGUF file. Arrays, such as the tokenizer vocabulary, are skipped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i128),
+    Float(f64),
+    Bool(bool),
+    String(String),
+}
+
+#[derive(Debug)]
+pub struct Metadata {
+    pub version: u32,
+    pub values: HashMap<String, Value>,
+}
+
+impl Metadata {
+    pub fn read(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open '{}'", path.display()))?;
+        let mut reader = Reader {
+            inner: BufReader::new(file),
+            version: 0,
+        };
+
+        let mut magic = [0; 4];
+        reader.inner.read_exact(&mut magic)?;
+        if &magic != GGUF_MAGIC {
+            anyhow::bail!("'{}' is not a GGUF file", path.display());
+        }
+
+        reader.version = reader.u32()?;
+        let _tensor_count = reader.count()?;
+        let kv_count = reader.count()?;
+
+        let mut values = HashMap::new();
+        for _ in 0..kv_count {
+            let key = reader.string()?;
+            let value_type = reader.u32()?;
+            if let Some(value) = reader.value(value_type)? {
+                values.insert(key, value);
+            }
+        }
+
+        Ok(Self {
+            version: reader.version,
+            values,
+        })
+    }
+
+    pub fn architecture(&self) -> Option<&str> {
+        match self.values.get("general.architecture") {
+            Some(Value::String(architecture)) => Some(architecture),
+            _ => None,
+        }
+    }
+
+    /// Reads an integer of the model's architecture, e.g. `context_length` for
+    /// `llama.context_length`.
+    pub fn arch_int(&self, key: &str) -> Option<u64> {
+        let key = format!("{}.{}", self.architecture()?, key);
+        match self.values.get(&key) {
+            Some(Value::Int(value)) => u64::try_from(*value).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn context_length(&self) -> Option<u64> {
+        self.arch_int("context_length")
+    }
+
+    /// Approximate size of the f16 KV cache for `ctx_size` tokens.
+    pub fn kv_cache_bytes(&self, ctx_size: u64) -> Option<u64> {
+        let layers = self.arch_int("block_count")?;
+        let embedding = self.arch_int("embedding_length")?;
+        let heads = self.arch_int("attention.head_count")?;
+        let kv_heads = self.arch_int("attention.head_count_kv").unwrap_or(heads);
+        if heads == 0 {
+            return None;
+        }
+
+        let kv_embedding = embedding * kv_heads / heads;
+        Some(2 * layers * ctx_size * kv_embedding * 2)
+    }
+}
+
+struct Reader {
+    inner: BufReader<File>,
+    version: u32,
+}
+
+impl Reader {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.inner.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    /// Counts and lengths are 32 bits wide in GGUF v1, 64 bits since.
+    fn count(&mut self) -> Result<u64> {
+        if self.version == 1 {
+            Ok(self.u32()? as u64)
+        } else {
+            self.u64()
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.count()? as usize;
+        let mut buf = vec![0; len];
+        self.inner.read_exact(&mut buf)?;
+        Ok(String::from_utf8_lossy(&buf).to_string())
+    }
+
+    fn skip(&mut self, len: u64) -> Result<()> {
+        self.inner.seek_relative(len as i64)?;
+        Ok(())
+    }
+
+    fn value(&mut self, value_type: u32) -> Result<Option<Value>> {
+        let value = match value_type {
+            0 => Value::Int(u8::from_le_bytes(self.bytes()?) as i128),
+            1 => Value::Int(i8::from_le_bytes(self.bytes()?) as i128),
+            2 => Value::Int(u16::from_le_bytes(self.bytes()?) as i128),
+            3 => Value::Int(i16::from_le_bytes(self.bytes()?) as i128),
+            4 => Value::Int(self.u32()? as i128),
+            5 => Value::Int(i32::from_le_bytes(self.bytes()?) as i128),
+            6 => Value::Float(f32::from_le_bytes(self.bytes()?) as f64),
+            7 => Value::Bool(self.bytes::<1>()?[0] != 0),
+            8 => Value::String(self.string()?),
+            9 => {
+                let item_type = self.u32()?;
+                let len = self.count()?;
+                self.skip_array(item_type, len)?;
+                return Ok(None);
+            }
+            10 => Value::Int(self.u64()? as i128),
+            11 => Value::Int(i64::from_le_bytes(self.bytes()?) as i128),
+            12 => Value::Float(f64::from_le_bytes(self.bytes()?)),
+            _ => anyhow::bail!("Unknown GGUF value type {}", value_type),
+        };
+
+        Ok(Some(value))
+    }
+
+    fn skip_array(&mut self, item_type: u32, len: u64) -> Result<()> {
+        let item_size = match item_type {
+            0 | 1 | 7 => 1,
+            2 | 3 => 2,
+            4..=6 => 4,
+            10..=12 => 8,
+            _ => 0,
+        };
+
+        if item_size > 0 {
+            return self.skip(item_size * len);
+        }
+
+        for _ in 0..len {
+            self.value(item_type)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `path` starts with the GGUF magic bytes.
+pub fn is_gguf(path: &Path) -> Result<bool> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open '{}'", path.display()))?;
+    let mut magic = [0; 4];
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == GGUF_MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Checks that a downloaded file starts with the GGUF magic, explaining the usual culprits when
+/// it doesn't: a Git LFS pointer or an HTML error page served instead of the model.
+pub fn check_magic(path: &Path) -> Result<()> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open '{}'", path.display()))?;
+    let mut head = Vec::with_capacity(64);
+    file.by_ref().take(64).read_to_end(&mut head)?;
+    if head.starts_with(GGUF_MAGIC) {
+        return Ok(());
+    }
+
+    let text = String::from_utf8_lossy(&head)
+        .trim_start()
+        .to_ascii_lowercase();
+    if text.starts_with("version https://git-lfs") {
+        anyhow::bail!(
+            "'{}' is a Git LFS pointer rather than the model, download it from a `resolve` URL instead",
+            path.display()
+        );
+    }
+    if text.starts_with("<!doctype") || text.starts_with("<html") {
+        anyhow::bail!(
+            "'{}' is an HTML page rather than the model, check the URL",
+            path.display()
+        );
+    }
+    anyhow::bail!("'{}' is not a GGUF file", path.display())
+}
+
+/// Checks the requested context size against the one the model was trained with, warning or,
+/// if `strict`, failing when it's larger.
+pub fn validate_context_size(model_path: &Path, ctx_size: u64, strict: bool) -> Result<()> {
+    let metadata = Metadata::read(model_path)?;
+
+    if let Some(kv_cache) = metadata.kv_cache_bytes(ctx_size) {
+        info!(
+            "A context of {} tokens needs about {} of KV cache",
+            ctx_size,
+            indicatif::HumanBytes(kv_cache)
+        );
+    }
+
+    match metadata.context_length() {
+        Some(trained) if ctx_size > trained => {
+            let message = format!(
+                "Context size {} is larger than the {} tokens {} was trained with",
+                ctx_size,
+                trained,
+                model_path.display()
+            );
+            if strict {
+                anyhow::bail!(message);
+            }
+            warn!("{}", message);
+        }
+        Some(_) => {}
+        None => warn!(
+            "Couldn't read the trained context length of {}",
+            model_path.display()
+        ),
+    }
+
+    Ok(())
+}
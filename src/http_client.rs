@@ -1,97 +1,757 @@
-use std::{fs::OpenOptions, io::Write, path::Path};
+use anyhow::Context;
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
 
 use bytes::Bytes;
 use futures_util::{Stream, StreamExt};
-use indicatif::{ProgressBar, ProgressStyle};
-use reqwest::{Client, Error};
-use serde::de::DeserializeOwned;
+use indicatif::{
+    HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressState, ProgressStyle,
+};
+use log::{debug, info, warn};
+use reqwest::{
+    header::{RANGE, USER_AGENT},
+    redirect::Policy,
+    Client, Error, RequestBuilder, StatusCode, Url,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{checksum, manifest::Manifest, output};
+
+/// Attempts after the first failure of a request, by default.
+const DEFAULT_MAX_RETRIES: u32 = 3;
+/// Delay before the first retry, doubled before each of the next ones.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Default for both the connection timeout and the longest wait for more data.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Smallest range fetched by a worker of a parallel download.
+const MIN_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Downloads smaller than this are too short to measure throughput.
+const MIN_THROUGHPUT_SAMPLE: u64 = 16 * 1024 * 1024;
+
+/// Identifies the launcher and its version, which is the recommended User-Agent. Mirrors that
+/// reject it can be given another one, or none, by a header rule.
+const DEFAULT_USER_AGENT: &str = concat!("llamafile-launcher/", env!("CARGO_PKG_VERSION"));
+
+static CONFIG: OnceLock<HttpConfig> = OnceLock::new();
+/// Draws the progress bars of concurrent downloads below one another.
+static PROGRESS: OnceLock<MultiProgress> = OnceLock::new();
+/// Number of retries per host over the whole run, to tell flaky networks apart.
+static RETRIES: Mutex<BTreeMap<String, u32>> = Mutex::new(BTreeMap::new());
+
+/// Settings shared by every `HttpClient` of the process.
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    pub user_agent: String,
+    pub max_redirects: usize,
+    /// Retries of requests failing with network or server errors.
+    pub max_retries: u32,
+    /// Concurrent range requests of a download, where the server supports them.
+    pub download_threads: usize,
+    /// Longest time to connect to a server, or to wait for more data of a response.
+    pub timeout: Duration,
+    /// Base URL of a read-through cache that downloads are tried from first.
+    pub cache_proxy: Option<String>,
+    /// Hides progress bars, e.g. when the output is logged.
+    pub no_progress: bool,
+    pub header_rules: Vec<HeaderRule>,
+}
+
+/// Headers sent with requests whose URL starts with `prefix`, such as credentials for a
+/// private artifact server. Only the first matching rule applies. A `User-Agent` header
+/// replaces the configured one, and an empty `User-Agent` sends none.
+#[derive(Deserialize, Clone)]
+pub struct HeaderRule {
+    pub prefix: String,
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Only shows header names, as values are usually secrets.
+impl std::fmt::Debug for HeaderRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HeaderRule")
+            .field("prefix", &self.prefix)
+            .field("headers", &self.headers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            max_redirects: 10,
+            max_retries: DEFAULT_MAX_RETRIES,
+            download_threads: 1,
+            timeout: DEFAULT_TIMEOUT,
+            cache_proxy: None,
+            no_progress: false,
+            header_rules: Vec::new(),
+        }
+    }
+}
+
+/// Sets the configuration of all `HttpClient`s created afterwards. Only the first call has an
+/// effect.
+pub fn configure(config: HttpConfig) {
+    if CONFIG.set(config).is_err() {
+        warn!("HTTP client already configured, ignoring new configuration");
+    }
+}
+
+/// Counts a retry of a request to `url` against its host.
+pub fn record_retry(url: &str) {
+    let host = host_of(url);
+    *RETRIES.lock().unwrap().entry(host).or_default() += 1;
+}
+
+/// Retries per host so far.
+pub fn retries() -> BTreeMap<String, u32> {
+    RETRIES.lock().unwrap().clone()
+}
+
+/// Logs how many retries each host needed, if any did.
+pub fn log_retry_summary() {
+    let retries = retries();
+    if retries.is_empty() {
+        return;
+    }
+
+    let summary = retries
+        .iter()
+        .map(|(host, count)| match count {
+            1 => format!("1 retry on {}", host),
+            n => format!("{} retries on {}", n, host),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    info!("Network retries: {}", summary);
+}
 
 #[derive(Debug)]
 pub struct HttpClient {
     client: Client,
+    no_redirect_client: Client,
+    user_agent: String,
+    cache_proxy: Option<String>,
+    header_rules: Vec<HeaderRule>,
+    throughput_history: Option<PathBuf>,
+    /// Retries of downloads and `get`s failing with network or server errors, with
+    /// exponential backoff.
+    max_retries: u32,
+    download_threads: usize,
+    idle_timeout: Duration,
+}
+
+/// A failure worth retrying: the network failed or the server errored, as opposed to a
+/// client error such as a 404 that fails the same way every time.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+struct Transient(String);
+
+/// The server requires credentials that weren't given, or don't grant access (401 or 403).
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+struct Unauthorized(String);
+
+/// A secret given on the command line, hidden from debug output.
+#[derive(Clone)]
+pub struct Secret(pub String);
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl std::str::FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_string()))
+    }
 }
 
 impl HttpClient {
     pub fn new() -> Self {
+        let config = CONFIG.get_or_init(HttpConfig::default);
+
         Self {
-            client: Client::new(),
+            client: Client::builder()
+                .connect_timeout(config.timeout)
+                .redirect(redirect_policy(config.max_redirects))
+                .build()
+                .expect("Failed to build HTTP client"),
+            no_redirect_client: Client::builder()
+                .connect_timeout(config.timeout)
+                .redirect(Policy::none())
+                .build()
+                .expect("Failed to build HTTP client"),
+            user_agent: config.user_agent.clone(),
+            cache_proxy: config.cache_proxy.clone(),
+            header_rules: config.header_rules.clone(),
+            throughput_history: None,
+            max_retries: config.max_retries,
+            download_threads: config.download_threads,
+            idle_timeout: config.timeout,
+        }
+    }
+
+    /// Waits before retry number `attempt` of a request to `url` that failed with `e`.
+    async fn backoff(&self, url: &str, attempt: u32, e: &anyhow::Error) {
+        let delay = INITIAL_BACKOFF.saturating_mul(2u32.saturating_pow(attempt - 1));
+        warn!(
+            "{:#}, retrying in {}s ({}/{})",
+            e,
+            delay.as_secs(),
+            attempt,
+            self.max_retries
+        );
+        record_retry(url);
+        tokio::time::sleep(delay).await;
+    }
+
+    /// Records the throughput of downloads in the manifest of `models_dir`, to estimate how
+    /// long later downloads from the same hosts take.
+    pub fn set_throughput_history(&mut self, models_dir: PathBuf) {
+        self.throughput_history = Some(models_dir);
+    }
+
+    fn recent_throughput(&self, host: &str) -> Option<u64> {
+        let manifest = Manifest::load(self.throughput_history.as_ref()?).ok()?;
+        manifest
+            .throughput
+            .get(host)
+            .copied()
+            .filter(|rate| *rate > 0)
+    }
+
+    /// Averages the throughput of a download into the host's history.
+    fn record_throughput(&self, host: &str, bytes: u64, elapsed: Duration) -> anyhow::Result<()> {
+        let Some(models_dir) = self.throughput_history.as_ref() else {
+            return Ok(());
+        };
+        if bytes < MIN_THROUGHPUT_SAMPLE || elapsed.is_zero() {
+            return Ok(());
+        }
+
+        let rate = (bytes as f64 / elapsed.as_secs_f64()) as u64;
+        let mut manifest = Manifest::load(models_dir)?;
+        let recent = manifest.throughput.entry(host.to_string()).or_insert(rate);
+        *recent = (*recent + rate) / 2;
+        manifest.save()
+    }
+
+    /// Adds the User-Agent and the headers of the first rule matching `url`.
+    fn with_headers(&self, mut req: RequestBuilder, url: &str) -> RequestBuilder {
+        let mut user_agent = Some(self.user_agent.as_str());
+
+        if let Some(rule) = self
+            .header_rules
+            .iter()
+            .find(|rule| url.starts_with(&rule.prefix))
+        {
+            debug!("Using headers of rule {} for {}", rule.prefix, url);
+            for (name, value) in &rule.headers {
+                if name.eq_ignore_ascii_case(USER_AGENT.as_str()) {
+                    user_agent = Some(value.as_str()).filter(|value| !value.is_empty());
+                } else {
+                    req = req.header(name, value);
+                }
+            }
+        }
+
+        match user_agent {
+            Some(user_agent) => req.header(USER_AGENT, user_agent),
+            None => req,
         }
     }
 
+    /// Starts downloading `url` from byte `offset`. Returns the offset the server actually
+    /// resumed from, which is 0 if it doesn't support ranges, along with the total size if the
+    /// server announces it.
     pub async fn download(
         &mut self,
         url: &str,
-    ) -> anyhow::Result<(u64, impl Stream<Item = Result<Bytes, Error>>)> {
-        let res = self
-            .client
-            .get(url)
-            .header("User-Agent", "reqwest")
+        offset: u64,
+    ) -> anyhow::Result<(u64, Option<u64>, impl Stream<Item = Result<Bytes, Error>>)> {
+        let mut req = self.with_headers(self.client.get(url), url);
+        if offset > 0 {
+            req = req.header(RANGE, format!("bytes={}-", offset));
+        }
+
+        let res = req
             .send()
             .await
-            .or(Err(anyhow::anyhow!(format!(
-                "Failed to GET from '{}'",
-                &url
-            ))))?;
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| request_error(url, &e))?;
 
-        let total_size = res.content_length().ok_or(anyhow::anyhow!(format!(
-            "Failed to get content length from '{}'",
-            &url
-        )))?;
+        let offset = if res.status() == StatusCode::PARTIAL_CONTENT {
+            offset
+        } else {
+            0
+        };
+
+        // Servers using chunked transfer encoding don't announce the size.
+        let total_size = res
+            .content_length()
+            .map(|content_length| offset + content_length);
 
-        Ok((total_size, res.bytes_stream()))
+        Ok((offset, total_size, res.bytes_stream()))
     }
 
+    /// Downloads `url` to `path`, through a `.part` file that is resumed by later attempts if
+    /// the download is interrupted. With a cache proxy configured, the download is tried from
+    /// the cache first, falling back to `url` on a miss or an error.
     pub async fn download_to(
         &mut self,
         url: &str,
         path: &Path,
         set_executable: bool,
     ) -> anyhow::Result<()> {
-        let (total_size, mut stream) = self.download(url).await?;
+        self.download_to_verified(url, path, set_executable, None)
+            .await
+    }
 
-        let pb = ProgressBar::new(total_size);
-        pb.set_style(ProgressStyle::default_bar()
-        .template("{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})").unwrap()
-        .progress_chars("#>-"));
+    /// Like [`Self::download_to`], also hashing the download as it streams and failing if
+    /// its SHA-256 isn't `expected_sha256`, a hex digest. A mismatching `.part` file is
+    /// removed, so that the next attempt starts over.
+    pub async fn download_to_verified(
+        &mut self,
+        url: &str,
+        path: &Path,
+        set_executable: bool,
+        expected_sha256: Option<&str>,
+    ) -> anyhow::Result<()> {
+        output::check_writable(path)?;
+
+        if let Some(cache_url) = self.cache_proxy.as_deref().and_then(|p| cache_url(p, url)) {
+            match self
+                .download_from(&cache_url, path, set_executable, expected_sha256)
+                .await
+            {
+                Ok(()) => return Ok(()),
+                // Falling back isn't a retry of the origin, so it doesn't count towards them.
+                Err(e) => warn!("Cache proxy failed, downloading from origin: {}", e),
+            }
+        }
+
+        let mut attempt = 0;
+        loop {
+            match self
+                .download_from(url, path, set_executable, expected_sha256)
+                .await
+            {
+                Err(e) if attempt < self.max_retries && is_transient(&e) => {
+                    attempt += 1;
+                    self.backoff(url, attempt, &e).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn download_from(
+        &mut self,
+        url: &str,
+        path: &Path,
+        set_executable: bool,
+        expected_sha256: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let part_path = part_path(path);
+        let existing = std::fs::metadata(&part_path)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
 
-        pb.set_message(format!("Downloading {}", &url));
+        // A `.part` file is resumed by a single stream, as it's only ever written sequentially.
+        if existing == 0 && self.download_threads > 1 {
+            match self.ranged_length(url).await {
+                Some(total_size) if total_size >= 2 * MIN_CHUNK_SIZE => {
+                    return self
+                        .download_chunked(url, path, total_size, set_executable, expected_sha256)
+                        .await;
+                }
+                _ => debug!("Downloading {} in a single stream", url),
+            }
+        }
+
+        let (offset, total_size, mut stream) = self.download(url, existing).await?;
+
+        let host = host_of(url);
+        if let (Some(rate), Some(total_size)) = (self.recent_throughput(&host), total_size) {
+            let remaining = total_size.saturating_sub(offset);
+            info!(
+                "Downloading {} should take ~{} at your recent {}/s from {}",
+                HumanBytes(remaining),
+                HumanDuration(Duration::from_secs(remaining / rate)),
+                HumanBytes(rate),
+                host
+            );
+        }
+        let started = Instant::now();
+
+        let pb = match total_size {
+            Some(total_size) => progress_bar(total_size, offset),
+            None => progress_spinner(offset),
+        };
+
+        if offset > 0 {
+            pb.set_message(format!(
+                "Resuming download of {} from {}",
+                &url,
+                HumanBytes(offset)
+            ));
+        } else {
+            pb.set_message(format!("Downloading {}", &url));
+        }
 
         let mut options = OpenOptions::new();
         options.write(true).create(true);
+        if offset > 0 {
+            options.append(true);
+        } else {
+            options.truncate(true);
+        }
 
         #[cfg(target_family = "unix")]
         if set_executable {
             std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o755);
         }
 
-        options.open(path).or(Err(anyhow::anyhow!(format!(
+        let mut file = options.open(&part_path).or(Err(anyhow::anyhow!(format!(
             "Failed to open file '{}'",
-            &path.display()
+            &part_path.display()
         ))))?;
+        let mut downloaded = offset;
 
-        let mut file = std::fs::File::create(path)?;
-        let mut downloaded = 0;
+        // A resumed download is hashed from the bytes already in the `.part` file.
+        let mut hasher = match expected_sha256 {
+            Some(_) => Some(hash_prefix(&part_path, offset)?),
+            None => None,
+        };
 
-        while let Some(item) = stream.next().await {
-            let chunk = item.or(Err(anyhow::anyhow!(format!(
-                "Error while downloading file"
-            ))))?;
+        while let Some(chunk) = self.next_chunk(&mut stream, url).await? {
             file.write_all(&chunk)
                 .or(Err(anyhow::anyhow!(format!("Error while writing to file"))))?;
-            let new = std::cmp::min(downloaded + (chunk.len() as u64), total_size);
-            downloaded = new;
-            pb.set_position(new);
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&chunk);
+            }
+            downloaded += chunk.len() as u64;
+            if let Some(total_size) = total_size {
+                downloaded = downloaded.min(total_size);
+            }
+            pb.set_position(downloaded);
+        }
+
+        file.sync_all()?;
+        drop(file);
+
+        if let (Some(hasher), Some(expected)) = (hasher, expected_sha256) {
+            let actual = format!("{:x}", hasher.finalize());
+            if !actual.eq_ignore_ascii_case(expected) {
+                std::fs::remove_file(&part_path)?;
+                anyhow::bail!(
+                    "Checksum mismatch for '{}': expected {}, got {}",
+                    url,
+                    expected,
+                    actual
+                );
+            }
+            info!("Checksum of {} verified", path.display());
+        }
+
+        std::fs::rename(&part_path, path)?;
+
+        if let Err(e) = self.record_throughput(&host, downloaded - offset, started.elapsed()) {
+            warn!("Failed to record download throughput: {}", e);
+        }
+
+        pb.finish_with_message(format!(
+            "Downloaded {} ({}) to {}",
+            &url,
+            HumanBytes(downloaded),
+            &path.display()
+        ));
+        Ok(())
+    }
+
+    /// Waits for the next chunk of a download, failing if none arrives within the idle timeout
+    /// rather than hanging on a stalled connection.
+    async fn next_chunk(
+        &self,
+        stream: &mut (impl Stream<Item = Result<Bytes, Error>> + Unpin),
+        url: &str,
+    ) -> anyhow::Result<Option<Bytes>> {
+        match tokio::time::timeout(self.idle_timeout, stream.next()).await {
+            Ok(Some(Ok(chunk))) => Ok(Some(chunk)),
+            Ok(Some(Err(e))) => Err(Transient(format!(
+                "Error while downloading '{}': {}",
+                url,
+                error_chain(&e)
+            ))
+            .into()),
+            Ok(None) => Ok(None),
+            Err(_) => Err(Transient(format!(
+                "No data received from '{}' for {}s",
+                url,
+                self.idle_timeout.as_secs()
+            ))
+            .into()),
+        }
+    }
+
+    /// Returns the size of `url` if its server accepts byte ranges.
+    async fn ranged_length(&self, url: &str) -> Option<u64> {
+        let res = self
+            .with_headers(self.client.head(url), url)
+            .send()
+            .await
+            .ok()?
+            .error_for_status()
+            .ok()?;
+
+        let accepts_ranges = res
+            .headers()
+            .get(reqwest::header::ACCEPT_RANGES)
+            .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"bytes"));
+        if !accepts_ranges {
+            return None;
+        }
+
+        res.headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+    }
+
+    /// Downloads `url` as concurrent byte ranges written in place into a preallocated file.
+    /// Unlike a `.part` file, an interrupted parallel download starts over.
+    async fn download_chunked(
+        &self,
+        url: &str,
+        path: &Path,
+        total_size: u64,
+        set_executable: bool,
+        expected_sha256: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let chunks_path = chunks_path(path);
+        let threads = self
+            .download_threads
+            .min((total_size / MIN_CHUNK_SIZE) as usize);
+        let chunk_size = total_size.div_ceil(threads as u64);
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(target_family = "unix")]
+        if set_executable {
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o755);
+        }
+        options
+            .open(&chunks_path)
+            .and_then(|file| file.set_len(total_size))
+            .with_context(|| format!("Failed to create '{}'", chunks_path.display()))?;
+
+        let started = Instant::now();
+        let pb = progress_bar(total_size, 0);
+        pb.set_message(format!("Downloading {} in {} chunks", url, threads));
+
+        let ranges = (0..threads as u64).map(|i| {
+            let start = i * chunk_size;
+            let end = (start + chunk_size).min(total_size) - 1;
+            self.download_range(url, &chunks_path, start, end, &pb)
+        });
+        futures_util::future::try_join_all(ranges).await?;
+
+        if let Some(expected) = expected_sha256 {
+            let actual = checksum::sha256_file(&chunks_path)?;
+            if !actual.eq_ignore_ascii_case(expected) {
+                std::fs::remove_file(&chunks_path)?;
+                anyhow::bail!(
+                    "Checksum mismatch for '{}': expected {}, got {}",
+                    url,
+                    expected,
+                    actual
+                );
+            }
+            info!("Checksum of {} verified", path.display());
+        }
+
+        std::fs::rename(&chunks_path, path)?;
+
+        if let Err(e) = self.record_throughput(&host_of(url), total_size, started.elapsed()) {
+            warn!("Failed to record download throughput: {}", e);
         }
 
-        pb.finish_with_message(format!("Downloaded {} to {}", &url, &path.display()));
+        pb.finish_with_message(format!(
+            "Downloaded {} ({}) to {}",
+            &url,
+            HumanBytes(total_size),
+            &path.display()
+        ));
         Ok(())
     }
 
+    /// Writes the bytes `start..=end` of `url` at the same offset of `path`.
+    async fn download_range(
+        &self,
+        url: &str,
+        path: &Path,
+        start: u64,
+        end: u64,
+        pb: &ProgressBar,
+    ) -> anyhow::Result<()> {
+        let res = self
+            .with_headers(self.client.get(url), url)
+            .header(RANGE, format!("bytes={}-{}", start, end))
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| request_error(url, &e))?;
+        if res.status() != StatusCode::PARTIAL_CONTENT {
+            anyhow::bail!("'{}' ignored the range request", url);
+        }
+
+        // Every range has its own handle, so that their positions are independent.
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open file '{}'", path.display()))?;
+        file.seek(SeekFrom::Start(start))?;
+
+        let mut stream = res.bytes_stream();
+        while let Some(chunk) = self.next_chunk(&mut stream, url).await? {
+            file.write_all(&chunk)
+                .with_context(|| format!("Error while writing to '{}'", path.display()))?;
+            pb.inc(chunk.len() as u64);
+        }
+
+        file.sync_all()?;
+        Ok(())
+    }
+
+    pub async fn exists(&mut self, url: &str) -> anyhow::Result<bool> {
+        let res = self
+            .with_headers(self.client.head(url), url)
+            .send()
+            .await
+            .or(Err(anyhow::anyhow!(format!(
+                "Failed to HEAD from '{}'",
+                &url
+            ))))?;
+
+        Ok(res.status().is_success())
+    }
+
+    /// Returns the size of `url` announced by a HEAD request, following redirects.
+    pub async fn content_length(&mut self, url: &str) -> anyhow::Result<Option<u64>> {
+        let res = self
+            .with_headers(self.client.head(url), url)
+            .send()
+            .await
+            .or(Err(anyhow::anyhow!(format!(
+                "Failed to HEAD from '{}'",
+                &url
+            ))))?
+            .error_for_status()
+            .map_err(|e| anyhow::anyhow!(format!("Failed to HEAD from '{}': {}", &url, e)))?;
+
+        // The body of a HEAD response is empty, so the header has to be read directly.
+        Ok(res
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok()))
+    }
+
+    /// Returns the absolute target of a redirect response for `url`, without following it.
+    pub async fn redirect_location(&mut self, url: &str) -> anyhow::Result<Option<String>> {
+        let res = self
+            .with_headers(self.no_redirect_client.head(url), url)
+            .send()
+            .await
+            .or(Err(anyhow::anyhow!(format!(
+                "Failed to HEAD from '{}'",
+                &url
+            ))))?;
+
+        if !res.status().is_redirection() {
+            return Ok(None);
+        }
+
+        let location = match res.headers().get(reqwest::header::LOCATION) {
+            Some(location) => location.to_str()?,
+            None => return Ok(None),
+        };
+
+        Ok(Some(Url::parse(url)?.join(location)?.to_string()))
+    }
+
+    pub async fn get_text(&mut self, url: &str) -> anyhow::Result<String> {
+        let res = self
+            .with_headers(self.client.get(url), url)
+            .send()
+            .await
+            .or(Err(anyhow::anyhow!(format!(
+                "Failed to GET from '{}'",
+                &url
+            ))))?
+            .error_for_status()
+            .map_err(|e| request_error(url, &e))?;
+
+        let body = res.text().await.or(Err(anyhow::anyhow!(format!(
+            "Failed to read body from '{}'",
+            &url
+        ))))?;
+
+        Ok(body)
+    }
+
     pub async fn get<T: DeserializeOwned>(&mut self, url: &str) -> anyhow::Result<T> {
+        let mut attempt = 0;
+        loop {
+            match self.try_get(url).await {
+                Err(e) if attempt < self.max_retries && is_transient(&e) => {
+                    attempt += 1;
+                    self.backoff(url, attempt, &e).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn try_get<T: DeserializeOwned>(&mut self, url: &str) -> anyhow::Result<T> {
         let res = self
-            .client
-            .get(url)
-            .header("User-Agent", "reqwest")
+            .with_headers(self.client.get(url), url)
+            .send()
+            .await
+            .map_err(|e| request_error(url, &e))?;
+        if res.status().is_server_error() {
+            return Err(
+                Transient(format!("Failed to GET from '{}': {}", url, res.status())).into(),
+            );
+        }
+
+        let body = res.json::<T>().await.or(Err(anyhow::anyhow!(format!(
+            "Failed to parse JSON from '{}'",
+            &url
+        ))))?;
+
+        Ok(body)
+    }
+
+    pub async fn status(&mut self, url: &str) -> anyhow::Result<StatusCode> {
+        let res = self
+            .with_headers(self.client.get(url), url)
             .send()
             .await
             .or(Err(anyhow::anyhow!(format!(
@@ -99,6 +759,46 @@ impl HttpClient {
                 &url
             ))))?;
 
+        Ok(res.status())
+    }
+
+    pub async fn post_stream<B: Serialize>(
+        &mut self,
+        url: &str,
+        body: &B,
+    ) -> anyhow::Result<impl Stream<Item = Result<Bytes, Error>>> {
+        let res = self
+            .with_headers(self.client.post(url), url)
+            .json(body)
+            .send()
+            .await
+            .or(Err(anyhow::anyhow!(format!(
+                "Failed to POST to '{}'",
+                &url
+            ))))?
+            .error_for_status()
+            .map_err(|e| anyhow::anyhow!(format!("Failed to POST to '{}': {}", &url, e)))?;
+
+        Ok(res.bytes_stream())
+    }
+
+    pub async fn post<B: Serialize, T: DeserializeOwned>(
+        &mut self,
+        url: &str,
+        body: &B,
+    ) -> anyhow::Result<T> {
+        let res = self
+            .with_headers(self.client.post(url), url)
+            .json(body)
+            .send()
+            .await
+            .or(Err(anyhow::anyhow!(format!(
+                "Failed to POST to '{}'",
+                &url
+            ))))?
+            .error_for_status()
+            .map_err(|e| anyhow::anyhow!(format!("Failed to POST to '{}': {}", &url, e)))?;
+
         let body = res.json::<T>().await.or(Err(anyhow::anyhow!(format!(
             "Failed to parse JSON from '{}'",
             &url
@@ -107,3 +807,174 @@ impl HttpClient {
         Ok(body)
     }
 }
+
+/// Creates a byte progress bar starting at `offset`, hidden if progress is disabled.
+pub fn progress_bar(total: u64, offset: u64) -> ProgressBar {
+    if CONFIG.get_or_init(HttpConfig::default).no_progress {
+        return ProgressBar::hidden();
+    }
+
+    let pb = ProgressBar::new(total).with_position(offset);
+    pb.set_style(progress_style(offset));
+    PROGRESS.get_or_init(MultiProgress::new).add(pb)
+}
+
+/// Like [`progress_bar`], for downloads whose size isn't known.
+pub fn progress_spinner(offset: u64) -> ProgressBar {
+    if CONFIG.get_or_init(HttpConfig::default).no_progress {
+        return ProgressBar::hidden();
+    }
+
+    let pb = ProgressBar::new_spinner().with_position(offset);
+    pb.set_style(
+        ProgressStyle::default_spinner()
+            .template("{msg}\n{spinner:.green} [{elapsed_precise}] {bytes} ({bytes_per_sec})")
+            .unwrap()
+            .with_key(
+                "bytes_per_sec",
+                move |state: &ProgressState, w: &mut dyn std::fmt::Write| {
+                    let _ = write!(w, "{}/s", HumanBytes(transfer_rate(state, offset) as u64));
+                },
+            ),
+    );
+    pb.enable_steady_tick(Duration::from_millis(100));
+    PROGRESS.get_or_init(MultiProgress::new).add(pb)
+}
+
+/// Progress bar style whose speed and ETA only account for the bytes transferred since
+/// `offset`, so that resumed downloads don't report the already present bytes as throughput.
+fn progress_style(offset: u64) -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template("{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})").unwrap()
+        .with_key(
+            "bytes_per_sec",
+            move |state: &ProgressState, w: &mut dyn std::fmt::Write| {
+                let _ = write!(w, "{}/s", HumanBytes(transfer_rate(state, offset) as u64));
+            },
+        )
+        .with_key(
+            "eta",
+            move |state: &ProgressState, w: &mut dyn std::fmt::Write| {
+                let rate = transfer_rate(state, offset);
+                let remaining = state.len().unwrap_or(0).saturating_sub(state.pos());
+                let eta = if rate > 0.0 {
+                    Duration::from_secs_f64(remaining as f64 / rate)
+                } else {
+                    Duration::ZERO
+                };
+                let _ = write!(w, "{}", HumanDuration(eta));
+            },
+        )
+        .progress_chars("#>-")
+}
+
+/// Bytes per second transferred since `offset`.
+fn transfer_rate(state: &ProgressState, offset: u64) -> f64 {
+    let elapsed = state.elapsed().as_secs_f64();
+    if elapsed > 0.0 {
+        state.pos().saturating_sub(offset) as f64 / elapsed
+    } else {
+        0.0
+    }
+}
+
+/// Maps `url` to its location behind a read-through cache: `<proxy>/<host>/<path>?<query>`.
+fn cache_url(proxy: &str, url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let mut cache_url = format!(
+        "{}/{}{}",
+        proxy.trim_end_matches('/'),
+        url.host_str()?,
+        url.path()
+    );
+    if let Some(query) = url.query() {
+        cache_url.push('?');
+        cache_url.push_str(query);
+    }
+    Some(cache_url)
+}
+
+fn host_of(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(ToString::to_string))
+        .unwrap_or_default()
+}
+
+/// Hashes the first `len` bytes of `path`.
+fn hash_prefix(path: &Path, len: u64) -> anyhow::Result<Sha256> {
+    let mut hasher = Sha256::new();
+    if len > 0 {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open '{}' for hashing", path.display()))?;
+        std::io::copy(&mut std::io::Read::take(file, len), &mut hasher)?;
+    }
+    Ok(hasher)
+}
+
+/// Describes a failed request, marking it [`Transient`] unless the server rejected it with a
+/// client error.
+fn request_error(url: &str, e: &Error) -> anyhow::Error {
+    let message = format!("Failed to GET from '{}': {}", url, error_chain(e));
+    match e.status() {
+        Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => Unauthorized(message).into(),
+        Some(status) if !status.is_server_error() => anyhow::anyhow!(message),
+        None if e.is_builder() => anyhow::anyhow!(message),
+        _ => Transient(message).into(),
+    }
+}
+
+fn is_transient(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| cause.is::<Transient>())
+}
+
+/// Whether `e` is a request rejected for missing or insufficient credentials.
+pub fn is_unauthorized(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| cause.is::<Unauthorized>())
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut part_path = path.as_os_str().to_owned();
+    part_path.push(".part");
+    PathBuf::from(part_path)
+}
+
+/// Where a parallel download is written before it completes. It still ends in `.part`, so
+/// that it's excluded like other partial downloads.
+fn chunks_path(path: &Path) -> PathBuf {
+    let mut chunks_path = path.as_os_str().to_owned();
+    chunks_path.push(".chunks.part");
+    PathBuf::from(chunks_path)
+}
+
+/// Follows at most `max_redirects` redirects, logging every hop.
+fn redirect_policy(max_redirects: usize) -> Policy {
+    Policy::custom(move |attempt| {
+        if let Some(previous) = attempt.previous().last() {
+            debug!("Redirected from {} to {}", previous, attempt.url());
+        }
+
+        if attempt.previous().len() > max_redirects {
+            let message = format!(
+                "Too many redirects (more than {}), last to {}",
+                max_redirects,
+                attempt.url()
+            );
+            attempt.error(message)
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// Formats an error along with its sources, as reqwest hides the cause in them.
+fn error_chain(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
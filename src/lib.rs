@@ -0,0 +1,22 @@
+//! Downloads models, builds llamafiles and docker images serving them, and runs them.
+//!
+//! The command line interface, its config file and reports live in the `launcher` binary, and
+//! aren't part of the library.
+
+pub mod checksum;
+pub mod completion;
+pub mod compose;
+pub mod docker;
+pub mod gateway;
+pub mod gguf;
+pub mod http_client;
+pub mod llamafile_builder;
+pub mod manifest;
+pub mod models;
+pub mod output;
+pub mod runner;
+
+pub use crate::{
+    docker::Docker, http_client::HttpClient, llamafile_builder::LlamafileBuilder, models::Models,
+    runner::Runner,
+};
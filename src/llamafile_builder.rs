@@ -2,33 +2,71 @@ use anyhow::{Context, Result};
 use log::{debug, info, warn};
 use std::{
     fs::OpenOptions,
-    io::Write,
+    io::{IsTerminal, Write},
     path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
 };
 
 #[cfg(unix)]
 use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 
-use crate::http_client::HttpClient;
+use crate::{
+    http_client::{self, HttpClient},
+    output,
+};
+
+/// Only reachable from the machine itself, unless another host is given.
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+pub const DEFAULT_PORT: u16 = 8080;
 
-const LLAMAFILE_GITHUB_RELEASE_URL: &str =
-    "https://api.github.com/repos/Mozilla-Ocho/llamafile/releases/latest";
+const LLAMAFILE_GITHUB_RELEASES_URL: &str =
+    "https://api.github.com/repos/Mozilla-Ocho/llamafile/releases";
 
 pub struct LlamafileBuilder {
+    /// Removes the temporary directory when the builder is dropped, unless it's kept.
+    temp_dir: Option<tempfile::TempDir>,
     temp_path: PathBuf,
     output_dir: Option<PathBuf>,
     llamafile_path: PathBuf,
     zipalign_path: PathBuf,
+    confirm_breaking_updates: bool,
+    server_args: Vec<String>,
+    host: String,
+    port: u16,
+    default_model_index: usize,
+    /// Release tag assets are downloaded from, the latest release if `None`.
+    release_version: Option<String>,
+    /// GitHub API endpoint listing the releases.
+    releases_url: String,
+    /// The resolved release, fetched once for all assets.
+    release: Option<GithubRelease>,
+    /// Name of the server asset, chosen for the platform if `None`.
+    server_asset: Option<String>,
     http_client: HttpClient,
 }
 
 impl LlamafileBuilder {
+    /// Intermediate files are written to a unique directory within `work_dir`, or within the
+    /// system's temporary directory.
     pub async fn new(
         output_dir: Option<PathBuf>,
         llamafile_path: Option<PathBuf>,
         zipalign_path: Option<PathBuf>,
+        work_dir: Option<PathBuf>,
     ) -> Result<LlamafileBuilder> {
-        let temp_path = tempfile::tempdir()?.into_path();
+        let mut temp_builder = tempfile::Builder::new();
+        temp_builder.prefix("llamafile-build-");
+        let temp_dir = match work_dir.as_ref() {
+            Some(work_dir) => {
+                std::fs::create_dir_all(work_dir).with_context(|| {
+                    format!("Failed to create work directory {}", work_dir.display())
+                })?;
+                temp_builder.tempdir_in(work_dir)?
+            }
+            None => temp_builder.tempdir()?,
+        };
+        let temp_path = temp_dir.path().to_path_buf();
 
         if let Some(output_dir) = output_dir.as_ref() {
             if !output_dir.exists() {
@@ -38,42 +76,67 @@ impl LlamafileBuilder {
             info!("Using output directory {}", output_dir.display());
         }
 
-        if zipalign_path
-            .as_ref()
-            .is_some_and(|p| p.exists() && p.is_file())
-            && llamafile_path
-                .as_ref()
-                .is_some_and(|p| p.exists() && p.is_file())
-        {
-            let llamafile_path = llamafile_path.unwrap();
-            let zipalign_path = zipalign_path.unwrap();
-            info!("Using existing llamafile at {}", llamafile_path.display());
-            info!("Using existing zipalign at {}", zipalign_path.display());
-            return Ok(LlamafileBuilder {
-                temp_path,
-                output_dir,
-                llamafile_path,
-                zipalign_path,
-                http_client: HttpClient::new(),
-            });
-        }
-
-        info!("Using existing llamafile repo at {}", temp_path.display());
-        let llamafile_path = temp_path.join("llamafile-server");
-        let zipalign_path = temp_path.join("zipalign");
+        debug!("Using temporary directory {}", temp_path.display());
+        let llamafile_path = match llamafile_path {
+            Some(path) if path.is_file() => {
+                info!("Using existing llamafile at {}", path.display());
+                path
+            }
+            _ => temp_path.join("llamafile-server"),
+        };
+
+        let zipalign_path = match zipalign_path {
+            Some(path) if path.is_file() => {
+                info!("Using existing zipalign at {}", path.display());
+                path
+            }
+            _ => temp_path.join(executable_name("zipalign")),
+        };
 
         Ok(LlamafileBuilder {
+            temp_dir: Some(temp_dir),
             temp_path,
             output_dir,
             llamafile_path,
             zipalign_path,
+            confirm_breaking_updates: false,
+            server_args: Vec::new(),
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_PORT,
+            default_model_index: 0,
+            release_version: None,
+            releases_url: LLAMAFILE_GITHUB_RELEASES_URL.to_string(),
+            release: None,
+            server_asset: None,
             http_client: HttpClient::new(),
         })
     }
 
-    pub async fn build(&mut self, models: &[&Path], output: Option<PathBuf>) -> Result<()> {
+    pub async fn build(
+        &mut self,
+        models: &[&Path],
+        embedded_files: &[&Path],
+        output: Option<PathBuf>,
+    ) -> Result<PathBuf> {
         info!("Building models..");
         debug!("Models: {:?}", models);
+        let Some(default_model) = models.get(self.default_model_index) else {
+            anyhow::bail!(
+                "Default model index {} is out of range, only {} models are embedded",
+                self.default_model_index,
+                models.len()
+            );
+        };
+        debug!("Embedded files: {:?}", embedded_files);
+
+        // `.args` has one argument per line, so a line break would split an argument in two.
+        if let Some(arg) = self
+            .server_args
+            .iter()
+            .find(|arg| arg.contains(['\n', '\r']))
+        {
+            anyhow::bail!("Llamafile argument {:?} contains a line break", arg);
+        }
 
         if !self.llamafile_path.exists() {
             warn!("llamafile-server not found in {}", self.temp_path.display());
@@ -90,37 +153,67 @@ impl LlamafileBuilder {
         let mut output_llamafile = OpenOptions::new();
 
         #[cfg(unix)]
-        debug!("Setting permissions on output to 0o755");
-        output_llamafile.mode(0o755);
+        {
+            debug!("Setting permissions on output to 0o755");
+            output_llamafile.mode(0o755);
+        }
 
-        let mut output_llamafile = output_llamafile
-            .write(true)
-            .create_new(true)
-            .open(&output)?;
+        let mut output_llamafile = output::create(&output, &mut output_llamafile)?;
 
-        std::io::copy(&mut llamafile, &mut output_llamafile)?;
+        let expected_size = llamafile.metadata()?.len();
+        if reflink(&llamafile, &output_llamafile) {
+            debug!("Reflinked {}", self.llamafile_path.display());
+        } else {
+            std::io::copy(&mut llamafile, &mut output_llamafile)?;
+        }
         output_llamafile.sync_all()?;
+        let copied_size = output_llamafile.metadata()?.len();
         drop(output_llamafile);
 
+        // Zipaligning into a partial copy would produce a corrupt llamafile.
+        if copied_size != expected_size {
+            std::fs::remove_file(&output)?;
+            anyhow::bail!(
+                "Copied {} bytes of {} into {}, expected {}",
+                copied_size,
+                self.llamafile_path.display(),
+                output.display(),
+                expected_size
+            );
+        }
+
         let args_file_path = self.temp_path.join(".args");
-        let mut args_file = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(&args_file_path)
+        let mut args_file = output::create(&args_file_path, &mut OpenOptions::new())
             .context("Failed to create .args file")?;
 
+        let server_flag = if needs_server_flag(&self.llamafile_path) {
+            "--server\n"
+        } else {
+            ""
+        };
         args_file.write_all(
             format!(
                 r#"
--m
+{}-m
 {}
 --host
-0.0.0.0
+{}
+--port
+{}
 "#,
-                models[0].file_name().unwrap().to_str().unwrap()
+                server_flag,
+                default_model.file_name().unwrap().to_str().unwrap(),
+                self.host,
+                self.port
             )
             .as_bytes(),
         )?;
+        for arg in &self.server_args {
+            writeln!(args_file, "{}", arg)?;
+        }
+        // Arguments given to the llamafile are inserted last, so that a later `-m` selects
+        // another embedded model.
+        writeln!(args_file, "...")?;
         args_file.sync_all()?;
         drop(args_file);
 
@@ -132,25 +225,82 @@ impl LlamafileBuilder {
         }
 
         #[cfg(unix)]
-        debug!("Setting permissions on zipalign to 0o755");
-        let zipalign = OpenOptions::new().read(true).open(&self.zipalign_path)?;
-        zipalign.set_permissions(std::fs::Permissions::from_mode(0o755))?;
+        {
+            debug!("Setting permissions on zipalign to 0o755");
+            let zipalign = OpenOptions::new().read(true).open(&self.zipalign_path)?;
+            zipalign.set_permissions(std::fs::Permissions::from_mode(0o755))?;
+        }
 
         info!("Zipaligning models..");
         debug!("Zipalign: {}", self.zipalign_path.display());
         debug!("Llamafile: {}", output.display());
-        tokio::process::Command::new(self.zipalign_path.as_path())
+        // zipalign stores the files uncompressed, so the llamafile grows to about their size,
+        // which is the only progress it shows.
+        let mut expected_size = copied_size;
+        for file in models.iter().chain(embedded_files) {
+            expected_size += std::fs::metadata(file)?.len();
+        }
+        let pb = http_client::progress_bar(expected_size, copied_size);
+        pb.set_message(format!("Zipaligning into {}", output.display()));
+
+        let zipalign = tokio::process::Command::new(self.zipalign_path.as_path())
             .arg("-j0")
-            .arg(output)
-            .arg(models[0])
+            .arg(&output)
+            .args(models)
             .arg(args_file_path)
-            .spawn()?
-            .wait()
-            .await?;
+            .args(embedded_files)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::piped())
+            .output();
+        tokio::pin!(zipalign);
+        let zipalign = loop {
+            tokio::select! {
+                zipalign = &mut zipalign => break zipalign,
+                _ = tokio::time::sleep(Duration::from_millis(500)) => {
+                    if let Ok(metadata) = std::fs::metadata(&output) {
+                        pb.set_position(metadata.len());
+                    }
+                }
+            }
+        };
+        pb.finish_and_clear();
+        let zipalign = zipalign
+            .with_context(|| format!("Failed to start {}", self.zipalign_path.display()))?;
+
+        // A failed zipalign leaves a llamafile missing some of its files.
+        if !zipalign.status.success() {
+            std::fs::remove_file(&output)?;
+            anyhow::bail!(
+                "zipalign exited with {}: {}",
+                zipalign.status,
+                String::from_utf8_lossy(&zipalign.stderr).trim()
+            );
+        }
 
         info!("Finished building models");
+        // Shards of a single model aren't models of their own.
+        let sharded = models.iter().all(|model| {
+            model
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(crate::models::shard_suffix)
+                .is_some()
+        });
+        if models.len() > 1 && !sharded {
+            info!(
+                "{} serves {} by default, pass `-m <name>` to serve another embedded model: {}",
+                output.display(),
+                default_model.display(),
+                models
+                    .iter()
+                    .filter_map(|model| model.file_name())
+                    .map(|name| name.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
 
-        Ok(())
+        Ok(output)
     }
 
     async fn download_llamafile_github_release(
@@ -160,66 +310,552 @@ impl LlamafileBuilder {
         let file_path = match github_release {
             GithubReleaseAsset::LlamafileServer => &self.llamafile_path,
             GithubReleaseAsset::Zipalign => &self.zipalign_path,
+            GithubReleaseAsset::Quantize => {
+                anyhow::bail!("llamafile-quantize isn't part of the build")
+            }
         };
 
         self.download_llamafile_github_release_into(github_release, &file_path.clone())
             .await
     }
 
+    /// Downloads a release asset into `path`. If `path` was downloaded by a previous call, it is
+    /// only downloaded again when the release changed. An existing `path` is kept when the
+    /// update fails, e.g. because GitHub can't be reached.
     pub async fn download_llamafile_github_release_into(
         &mut self,
         github_release: GithubReleaseAsset,
         path: &Path,
     ) -> Result<()> {
+        if !path.exists() {
+            return self.update_github_release_into(github_release, path).await;
+        }
+        if read_release_tag(path).is_none() {
+            output::check_writable(path)?;
+        }
+
+        if let Err(err) = self.update_github_release_into(github_release, path).await {
+            warn!(
+                "Failed to update {}, keeping the existing one: {:#}",
+                path.display(),
+                err
+            );
+        }
+        Ok(())
+    }
+
+    async fn update_github_release_into(
+        &mut self,
+        github_release: GithubReleaseAsset,
+        path: &Path,
+    ) -> Result<()> {
+        let release = self.release().await?;
+
         if path.exists() {
-            anyhow::bail!("{} already exists", path.display());
+            match read_release_tag(path) {
+                Some(tag) if tag == release.tag_name => {
+                    info!("{} is up to date ({})", path.display(), tag);
+                    return Ok(());
+                }
+                Some(tag) => {
+                    info!(
+                        "Updating {} from {} to {}",
+                        path.display(),
+                        tag,
+                        release.tag_name
+                    );
+                    if !self.review_release_notes(&tag, &release)? {
+                        info!("Keeping {} at {}", path.display(), tag);
+                        return Ok(());
+                    }
+                }
+                None => {}
+            }
         }
 
-        let release: GithubRelease = self
-            .http_client
-            .get(LLAMAFILE_GITHUB_RELEASE_URL)
-            .await
-            .context("Failed to get latest llamafile release")?;
+        let asset = match (&github_release, self.server_asset.as_deref()) {
+            (GithubReleaseAsset::LlamafileServer, Some(name)) => {
+                let asset = release.assets.iter().find(|asset| asset.name == name);
+                let asset = asset.with_context(|| {
+                    format!(
+                        "Release {} has no asset {}, it has {}",
+                        release.tag_name,
+                        name,
+                        release
+                            .assets
+                            .iter()
+                            .map(|asset| asset.name.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                })?;
+                info!("Using {}, as requested", asset.name);
+                asset
+            }
+            _ => github_release
+                .find_in(&release)
+                .context("Failed to find asset in release")?,
+        };
 
-        let asset = release
-            .assets
-            .iter()
-            .find(|a| a.name.starts_with(github_release.to_string().as_str()))
-            .context("Failed to find asset in release")?;
+        let expected_sha256 = self.release_sha256(&release, &asset.name).await;
 
         info!("Downloading {}..", asset.name);
+        // Every asset is a program. On Windows, it's made runnable by its `.exe` name instead.
+        // A download that doesn't match the checksum is deleted rather than ever run. It's
+        // written next to `path` and renamed over it once complete, so that a failed update
+        // leaves the previous binary in place.
+        let mut download_name = std::ffi::OsString::from(".");
+        download_name.push(path.file_name().unwrap_or_default());
+        download_name.push(".download");
+        let download_path = path.with_file_name(download_name);
+        if download_path.exists() {
+            std::fs::remove_file(&download_path)?;
+        }
         self.http_client
-            .download_to(&asset.browser_download_url, path, false)
-            .await?;
+            .download_to_verified(
+                &asset.browser_download_url,
+                &download_path,
+                true,
+                expected_sha256.as_deref(),
+            )
+            .await
+            .with_context(|| format!("Failed to download {}", asset.name))?;
+        std::fs::rename(&download_path, path)
+            .with_context(|| format!("Failed to replace {}", path.display()))?;
+
+        std::fs::write(
+            release_tag_path(path),
+            format!("{}\n{}\n", release.tag_name, asset.name),
+        )
+        .context("Failed to record release tag")?;
 
         Ok(())
     }
 
+    /// Looks up the SHA-256 of `asset_name` in the checksums asset of the release, e.g.
+    /// `SHA256SUMS`, warning when there is none to verify the download against.
+    async fn release_sha256(
+        &mut self,
+        release: &GithubRelease,
+        asset_name: &str,
+    ) -> Option<String> {
+        let Some(checksums) = release
+            .assets
+            .iter()
+            .find(|asset| is_checksums_asset(&asset.name))
+        else {
+            warn!(
+                "Release {} publishes no checksums, {} won't be verified",
+                release.tag_name, asset_name
+            );
+            return None;
+        };
+
+        let sha256 = match self
+            .http_client
+            .get_text(&checksums.browser_download_url)
+            .await
+        {
+            Ok(text) => find_sha256(&text, asset_name),
+            Err(e) => {
+                warn!("Failed to get {}: {:#}", checksums.name, e);
+                return None;
+            }
+        };
+        if sha256.is_none() {
+            warn!(
+                "{} has no checksum of {}, it won't be verified",
+                checksums.name, asset_name
+            );
+        }
+        sha256
+    }
+
+    /// Fetches the pinned release, or the latest one.
+    async fn release(&mut self) -> Result<GithubRelease> {
+        if let Some(release) = self.release.as_ref() {
+            return Ok(release.clone());
+        }
+
+        let release: GithubRelease = match self.release_version.as_deref() {
+            Some(version) => self
+                .http_client
+                .get(&format!("{}/tags/{}", self.releases_url, version))
+                .await
+                .with_context(|| format!("Failed to get llamafile release {}", version))?,
+            None => self
+                .http_client
+                .get(&format!("{}/latest", self.releases_url))
+                .await
+                .context("Failed to get latest llamafile release")?,
+        };
+
+        self.release = Some(release.clone());
+        Ok(release)
+    }
+
+    /// Downloads assets from the release tagged `version`, e.g. `0.8.13`, rather than the
+    /// latest release, for reproducible builds.
+    pub fn set_release_version(&mut self, version: Option<String>) {
+        self.release_version = version;
+        self.release = None;
+    }
+
+    /// Downloads the server from the release asset named `name`, rather than the one chosen
+    /// for the platform.
+    pub fn set_server_asset(&mut self, name: Option<String>) {
+        self.server_asset = name;
+    }
+
+    /// Keeps the temporary directory, with the downloaded tools and `.args`, for debugging.
+    pub fn keep_temp(&mut self) {
+        if let Some(temp_dir) = self.temp_dir.take() {
+            info!(
+                "Keeping temporary directory {}",
+                temp_dir.into_path().display()
+            );
+        }
+    }
+
+    /// Asks for confirmation before updating to a release that may break the command line.
+    pub fn set_confirm_breaking_updates(&mut self, confirm: bool) {
+        self.confirm_breaking_updates = confirm;
+    }
+
+    /// Arguments written into the `.args` of built llamafiles, after the model.
+    pub fn set_server_args(&mut self, server_args: Vec<String>) {
+        self.server_args = server_args;
+    }
+
+    /// Address the server of built llamafiles listens on.
+    pub fn set_address(&mut self, host: String, port: u16) {
+        self.host = host;
+        self.port = port;
+    }
+
+    /// Selects which of the embedded models is served when the llamafile is run without `-m`.
+    pub fn set_default_model_index(&mut self, index: usize) {
+        self.default_model_index = index;
+    }
+
+    /// Logs an excerpt of the release notes, and warns when the update looks breaking.
+    /// Logs the release notes of an update, returning whether to go ahead with it.
+    fn review_release_notes(&self, current_tag: &str, release: &GithubRelease) -> Result<bool> {
+        let notes = release.body.as_deref().unwrap_or_default();
+        for line in notes.lines().filter(|line| !line.trim().is_empty()).take(5) {
+            info!("  {}", line.trim());
+        }
+
+        let mentions_breaking = notes.to_lowercase().contains("breaking");
+        let major_bump = is_major_bump(current_tag, &release.tag_name);
+        if !mentions_breaking && !major_bump {
+            return Ok(true);
+        }
+
+        warn!(
+            "llamafile {} may contain breaking changes, see https://github.com/Mozilla-Ocho/llamafile/releases/tag/{}",
+            release.tag_name, release.tag_name
+        );
+
+        if self.confirm_breaking_updates {
+            if !std::io::stdin().is_terminal() {
+                warn!(
+                    "Can't ask for confirmation as stdin isn't a terminal, staying on {}",
+                    current_tag
+                );
+                return Ok(false);
+            }
+
+            eprint!("Update to {} anyway? [y/N] ", release.tag_name);
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+            return Ok(answer.trim().eq_ignore_ascii_case("y"));
+        }
+
+        Ok(true)
+    }
+
     fn get_output_path(&self, model_path: &Path, output_path: Option<PathBuf>) -> Result<PathBuf> {
         if let Some(output_path) = output_path {
+            if let Some(parent) = output_path.parent() {
+                if !parent.as_os_str().is_empty() && !parent.exists() {
+                    info!("Creating output directory {}", parent.display());
+                    std::fs::create_dir_all(parent).with_context(|| {
+                        format!("Failed to create output directory {}", parent.display())
+                    })?;
+                }
+            }
             Ok(output_path)
         } else if let Some(output_dir) = &self.output_dir {
-            Ok(output_dir.join(model_path.file_stem().unwrap()))
+            Ok(output_dir.join(output_file_name(model_path)))
         } else {
             anyhow::bail!("Neither output_dir nor output_path were specified");
         }
     }
 }
 
-#[derive(serde::Deserialize, Debug)]
+/// Clones `src` into `dst` by sharing their extents, which is instant and doesn't use disk space
+/// on filesystems supporting reflinks (btrfs, XFS, ...). Returns false if it isn't supported.
+#[cfg(target_os = "linux")]
+fn reflink(src: &std::fs::File, dst: &std::fs::File) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    const FICLONE: libc::c_ulong = 0x40049409;
+
+    // SAFETY: both file descriptors are valid for the duration of the call.
+    unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE as _, src.as_raw_fd()) == 0 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_src: &std::fs::File, _dst: &std::fs::File) -> bool {
+    false
+}
+
+/// Names the llamafile built from `model_path` `<base>-<quant>.llamafile`, where the
+/// quantization is parsed from the model file name, and shard suffixes are dropped.
+pub fn output_file_name(model_path: &Path) -> String {
+    let stem = model_path.file_stem().unwrap().to_string_lossy();
+    let stem = strip_shard_suffix(&stem);
+
+    let quant = stem.rsplit(['.', '-']).find(|token| is_quant(token));
+
+    match quant.and_then(|quant| stem.rfind(quant).map(|pos| (quant, pos))) {
+        Some((quant, pos)) => {
+            let base = format!("{}{}", &stem[..pos], &stem[pos + quant.len()..]);
+            let base = base.trim_matches(['.', '-', '_']);
+            format!("{}-{}.llamafile", base, quant)
+        }
+        None => format!("{}.llamafile", stem),
+    }
+}
+
+/// Strips the `-00001-of-00003` suffix of sharded models.
+fn strip_shard_suffix(stem: &str) -> &str {
+    let mut parts = stem.rsplitn(4, '-');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(total), Some("of"), Some(index), Some(base))
+            if is_number(total) && is_number(index) =>
+        {
+            base
+        }
+        _ => stem,
+    }
+}
+
+fn is_number(token: &str) -> bool {
+    !token.is_empty() && token.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Recognizes GGUF quantization names such as `Q4_K_M`, `IQ2_XS`, `Q8_0` or `F16`.
+fn is_quant(token: &str) -> bool {
+    let token = token.to_ascii_uppercase();
+    if ["F16", "F32", "BF16"].contains(&token.as_str()) {
+        return true;
+    }
+
+    let Some(rest) = token.strip_prefix("IQ").or_else(|| token.strip_prefix('Q')) else {
+        return false;
+    };
+
+    let bits_len = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+    let suffix = &rest[bits_len..];
+    bits_len > 0
+        && (suffix.is_empty()
+            || (suffix.starts_with('_')
+                && suffix
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')))
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
 struct GithubAsset {
     name: String,
     browser_download_url: String,
 }
 
-#[derive(serde::Deserialize, Debug)]
+#[derive(serde::Deserialize, Debug, Clone)]
 struct GithubRelease {
+    tag_name: String,
+    body: Option<String>,
     assets: Vec<GithubAsset>,
 }
 
+/// Whether going from `current` to `new` bumps the major version, or the minor version of 0.x
+/// releases, which semver allows to break compatibility.
+fn is_major_bump(current: &str, new: &str) -> bool {
+    fn version(tag: &str) -> Option<(u64, u64)> {
+        let mut parts = tag.trim_start_matches('v').split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next().unwrap_or("0").parse().ok()?;
+        Some((major, minor))
+    }
+
+    match (version(current), version(new)) {
+        (Some((0, current_minor)), Some((0, new_minor))) => new_minor > current_minor,
+        (Some((current_major, _)), Some((new_major, _))) => new_major > current_major,
+        _ => false,
+    }
+}
+
+/// Words of asset names for each operating system and architecture, as named by
+/// [`std::env::consts`].
+const OS_NAMES: &[(&str, &[&str])] = &[
+    ("linux", &["linux"]),
+    ("macos", &["macos", "darwin", "osx", "apple"]),
+    ("windows", &["windows", "win", "win64"]),
+    ("freebsd", &["freebsd"]),
+    ("openbsd", &["openbsd"]),
+    ("netbsd", &["netbsd"]),
+];
+const ARCH_NAMES: &[(&str, &[&str])] = &[
+    ("x86_64", &["x86_64", "amd64", "x64"]),
+    ("aarch64", &["aarch64", "arm64"]),
+];
+
+/// Words of an asset name, split on `-` and `.` so that `x86_64` stays one word.
+fn name_words(name: &str) -> Vec<String> {
+    name.to_ascii_lowercase()
+        .split(['-', '.'])
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// The operating system or architecture of `table` that an asset name mentions, if any.
+fn mentioned<'a>(words: &[String], table: &[(&'a str, &[&str])]) -> Option<&'a str> {
+    table.iter().find_map(|(platform, names)| {
+        names
+            .iter()
+            .any(|name| words.iter().any(|word| word == name))
+            .then_some(*platform)
+    })
+}
+
+/// Whether an asset is built for this operating system, and this architecture unless it
+/// doesn't name one.
+fn is_for_host(name: &str) -> bool {
+    let words = name_words(name);
+    mentioned(&words, OS_NAMES) == Some(std::env::consts::OS)
+        && !matches!(mentioned(&words, ARCH_NAMES), Some(arch) if arch != std::env::consts::ARCH)
+}
+
+/// Whether an asset names no platform, like the portable executables llamafile releases.
+fn is_portable(name: &str) -> bool {
+    let words = name_words(name);
+    mentioned(&words, OS_NAMES).is_none() && mentioned(&words, ARCH_NAMES).is_none()
+}
+
+/// Whether a release asset lists the checksums of the others, like `SHA256SUMS` or
+/// `checksums.txt`.
+fn is_checksums_asset(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.contains("sha256") || name.contains("checksum")
+}
+
+/// Finds the SHA-256 of `file_name` in the output of `sha256sum`, whose lines are
+/// `<hex digest>  <file name>`, with a `*` before binary file names.
+fn find_sha256(checksums: &str, file_name: &str) -> Option<String> {
+    checksums.lines().find_map(|line| {
+        let (digest, name) = line.trim().split_once(char::is_whitespace)?;
+        let name = name.trim_start().trim_start_matches('*');
+        (name == file_name && digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()))
+            .then(|| digest.to_ascii_lowercase())
+    })
+}
+
+/// Whether `path` was downloaded from a release by the launcher, and may be updated.
+pub fn is_release_download(path: &Path) -> bool {
+    release_tag_path(path).exists()
+}
+
+fn release_tag_path(path: &Path) -> PathBuf {
+    let mut tag_path = path.as_os_str().to_owned();
+    tag_path.push(".release");
+    PathBuf::from(tag_path)
+}
+
+/// Reads the release tag and asset name recorded when `path` was downloaded.
+fn read_release_record(path: &Path) -> Option<(String, Option<String>)> {
+    let record = std::fs::read_to_string(release_tag_path(path)).ok()?;
+    let mut lines = record.lines().map(str::trim);
+    let tag = lines.next()?.to_string();
+    let asset = lines.next().map(ToString::to_string);
+    Some((tag, asset))
+}
+
+fn read_release_tag(path: &Path) -> Option<String> {
+    read_release_record(path).map(|(tag, _)| tag)
+}
+
+/// Whether the server binary at `path` is the merged `llamafile` binary, which needs
+/// `--server` to run the server, rather than the legacy `llamafile-server`.
+pub fn needs_server_flag(path: &Path) -> bool {
+    let name = match read_release_record(path) {
+        Some((_, Some(asset))) => asset,
+        _ => path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    };
+
+    !name.starts_with("llamafile-server")
+}
+
+/// `name` with the platform's executable suffix, `.exe` on Windows, which Windows requires to
+/// run a program.
+pub fn executable_name(name: &str) -> String {
+    format!("{}{}", name, std::env::consts::EXE_SUFFIX)
+}
+
 pub enum GithubReleaseAsset {
     LlamafileServer,
     Zipalign,
+    Quantize,
+}
+
+impl GithubReleaseAsset {
+    /// Finds the asset in a release. The server is looked up under its legacy
+    /// `llamafile-server-<version>` name, then as the merged `llamafile-<version>` binary.
+    /// Among assets built for several platforms, the one for this platform is preferred, then
+    /// the portable binary that runs everywhere.
+    fn find_in<'a>(&self, release: &'a GithubRelease) -> Option<&'a GithubAsset> {
+        let prefixes: &[&str] = match self {
+            GithubReleaseAsset::LlamafileServer => &["llamafile-server-", "llamafile-"],
+            GithubReleaseAsset::Zipalign => &["zipalign-"],
+            GithubReleaseAsset::Quantize => &["llamafile-quantize-"],
+        };
+
+        prefixes.iter().find_map(|prefix| {
+            let candidates: Vec<&GithubAsset> = release
+                .assets
+                .iter()
+                .filter(|asset| {
+                    !asset.name.ends_with(".zip")
+                        && !is_checksums_asset(&asset.name)
+                        && asset.name.strip_prefix(prefix).is_some_and(|version| {
+                            version.starts_with(|c: char| c.is_ascii_digit())
+                        })
+                })
+                .collect();
+
+            let platform = format!("{} {}", std::env::consts::OS, std::env::consts::ARCH);
+            if let Some(asset) = candidates.iter().find(|asset| is_for_host(&asset.name)) {
+                info!("Chose {}, built for {}", asset.name, platform);
+                return Some(*asset);
+            }
+            let asset = candidates.iter().find(|asset| is_portable(&asset.name))?;
+            if candidates.len() > 1 {
+                info!(
+                    "Chose {}, the portable binary, as no {} asset is built for {}",
+                    asset.name,
+                    self.to_string(),
+                    platform
+                );
+            } else {
+                debug!("Chose {}, the portable binary", asset.name);
+            }
+            Some(*asset)
+        })
+    }
 }
 
 impl ToString for GithubReleaseAsset {
@@ -227,7 +863,76 @@ impl ToString for GithubReleaseAsset {
         match self {
             GithubReleaseAsset::LlamafileServer => "llamafile-server",
             GithubReleaseAsset::Zipalign => "zipalign",
+            GithubReleaseAsset::Quantize => "llamafile-quantize",
         }
         .to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn get_output_path_creates_missing_parent() {
+        let dir = tempfile::tempdir().unwrap();
+        let builder = LlamafileBuilder::new(None, None, None, Some(dir.path().to_path_buf()))
+            .await
+            .unwrap();
+        let output = dir
+            .path()
+            .join("missing")
+            .join("nested")
+            .join("model.llamafile");
+
+        let path = builder
+            .get_output_path(Path::new("model.gguf"), Some(output.clone()))
+            .unwrap();
+
+        assert_eq!(path, output);
+        assert!(output.parent().unwrap().is_dir());
+    }
+
+    #[tokio::test]
+    async fn download_keeps_existing_binary_when_github_is_unreachable() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut builder = LlamafileBuilder::new(None, None, None, Some(dir.path().to_path_buf()))
+            .await
+            .unwrap();
+        builder.releases_url = "http://127.0.0.1:1/releases".to_string();
+        builder.http_client.max_retries = 0;
+        let path = dir.path().join("llamafile-server");
+        std::fs::write(&path, "existing").unwrap();
+        std::fs::write(release_tag_path(&path), "0.8.13\nllamafile-0.8.13\n").unwrap();
+
+        builder
+            .download_llamafile_github_release_into(GithubReleaseAsset::LlamafileServer, &path)
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "existing");
+    }
+
+    #[test]
+    fn output_file_name_drops_shard_suffix() {
+        assert_eq!(
+            output_file_name(Path::new(
+                "Mixtral-8x7B-Instruct-v0.1.Q4_K_M-00001-of-00003.gguf"
+            )),
+            "Mixtral-8x7B-Instruct-v0.1-Q4_K_M.llamafile"
+        );
+    }
+
+    #[test]
+    fn output_file_name_without_quant() {
+        assert_eq!(output_file_name(Path::new("phi-2.gguf")), "phi-2.llamafile");
+    }
+
+    #[test]
+    fn output_file_name_keeps_mixed_case_quant() {
+        assert_eq!(
+            output_file_name(Path::new("llama-3-8b.Q5_k_m.gguf")),
+            "llama-3-8b-Q5_k_m.llamafile"
+        );
+    }
+}
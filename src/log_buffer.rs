@@ -0,0 +1,27 @@
+use std::{io::Write, sync::Mutex};
+
+static BUFFER: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+/// Log target holding the logs in memory until [`flush_to_stderr`] is called.
+struct BufferWriter;
+
+impl Write for BufferWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        BUFFER.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn writer() -> Box<dyn Write + Send> {
+    Box::new(BufferWriter)
+}
+
+/// Writes out the buffered logs, if any.
+pub fn flush_to_stderr() {
+    let buffer = std::mem::take(&mut *BUFFER.lock().unwrap());
+    let _ = std::io::stderr().write_all(&buffer);
+}
@@ -1,22 +1,94 @@
-use anyhow::Result;
-use clap::Parser;
-use log::{debug, error, info};
+use anyhow::Context;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use futures_util::StreamExt;
+use log::{debug, error, info, warn};
 use std::{
+    io::IsTerminal,
     path::{Path, PathBuf},
     process::exit,
+    time::{Duration, Instant},
 };
 
-mod docker;
-mod http_client;
-mod llamafile_builder;
-mod models;
+mod apply;
+mod bench;
+mod config;
+mod log_buffer;
+mod plan;
+mod quantize;
+mod report;
+mod systemd;
 
-use crate::{llamafile_builder::LlamafileBuilder, models::Models};
+// Imported at the root, so that the modules of the binary reach them as `crate::<module>` too.
+use launcher::{
+    completion, compose, docker, gateway, gguf, http_client, llamafile_builder, models, output,
+    runner,
+};
+
+use launcher::{manifest::Manifest, LlamafileBuilder, Models, Runner};
+
+const SMOKE_TEST_TIMEOUT: Duration = Duration::from_secs(300);
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(
+        last = true,
+        value_name = "SERVER_ARGS",
+        help = "Arguments passed verbatim to llamafile-server with --execute, after `--`"
+    )]
+    extra_server_args: Vec<String>,
+
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Ignore environment variables, only use arguments given on the command line"
+    )]
+    no_env: bool,
+
+    #[arg(
+        long,
+        env,
+        default_value = "false",
+        help = "Only output logs if the run fails"
+    )]
+    quiet_success: bool,
+
+    #[arg(
+        short,
+        long,
+        env,
+        default_value = "false",
+        help = "Only log warnings and errors, and don't show progress bars"
+    )]
+    quiet: bool,
+
+    #[arg(
+        long,
+        env = "LAUNCHER_CONFIG",
+        help = "Config file, whose settings are overridden by flags and environment variables [default: ~/.config/llamafile-launcher/config.toml]"
+    )]
+    config: Option<String>,
+
+    #[arg(
+        long,
+        env,
+        default_value = "false",
+        help = "Don't show progress bars for downloads and checksums, the default when stderr isn't a terminal"
+    )]
+    no_progress: bool,
+
+    #[arg(
+        long,
+        env,
+        default_value = "false",
+        help = "Replace existing outputs instead of failing"
+    )]
+    overwrite: bool,
+
     #[clap(flatten)]
     args: ModelSource,
 
@@ -24,6 +96,7 @@ struct Args {
     build_args: BuildArgs,
 
     #[arg(
+        help_heading = "Download",
         short = 'd',
         long,
         env,
@@ -32,288 +105,2115 @@ struct Args {
     model_dir: Option<String>,
 
     #[arg(
-        short = 'e',
+        help_heading = "Download",
         long,
-        default_value = "false",
         env,
-        help = "Execute the model"
+        value_enum,
+        default_value_t,
+        help = "Layout of Hugging Face models in the models directory"
     )]
-    execute: bool,
+    model_layout: models::Layout,
 
-    #[arg(short, long, env, help = "Path to llamafile-server")]
-    llamafile_server_path: Option<String>,
+    #[arg(
+        help_heading = "Download",
+        long,
+        env,
+        value_enum,
+        default_value_t,
+        help = "How Hugging Face files are stored in the models directory"
+    )]
+    cache_mode: models::CacheMode,
 
     #[arg(
-        short = 'b',
+        help_heading = "Download",
         long,
         env,
-        help = "Build docker image with the model",
-        default_value = "false"
+        value_parser = parse_size,
+        help = "Maximum size of the models directory, e.g. 100G, evicting the least recently used models to make room"
     )]
-    docker_build: bool,
+    cache_max_size: Option<u64>,
 
     #[arg(
+        help_heading = "Download",
         long,
         env,
-        help = "Image name for the docker image",
-        requires("docker_build")
+        help = "Hugging Face mirror endpoint, used when downloads from huggingface.co fail"
     )]
-    image_name: Option<String>,
-}
+    hf_mirror: Option<String>,
 
-#[derive(Debug, clap::Args)]
-#[group(required = false, multiple = true)]
-struct BuildArgs {
     #[arg(
-        short = 'B',
+        help_heading = "Download",
+        long,
+        env = "HF_TOKEN",
+        hide_env_values = true,
+        help = "Hugging Face token, for private and gated repositories"
+    )]
+    hf_token: Option<http_client::Secret>,
+
+    #[arg(
+        help_heading = "Download",
+        long,
+        default_value = "false",
+        help = "Bypass cached Hugging Face repository listings"
+    )]
+    refresh: bool,
+
+    #[arg(
+        help_heading = "Download",
         long,
         env,
-        help = "Build llamafile with embedded model",
         default_value = "false",
-        requires("llamafile_output")
+        help = "Use Hugging Face models already downloaded by huggingface_hub, from $HF_HUB_CACHE, $HF_HOME/hub or ~/.cache/huggingface/hub"
     )]
-    build_llamafile: bool,
+    local_hf_cache: bool,
 
     #[arg(
-        short = 'o',
+        help_heading = "Download",
         long,
         env,
-        help = "Output file of llamafile build",
-        requires("build_llamafile"),
-        conflicts_with("llamafile_output_dir")
+        default_value = "false",
+        help = "Don't check that downloaded .gguf files start with the GGUF magic"
     )]
-    llamafile_output: Option<String>,
+    skip_gguf_check: bool,
 
     #[arg(
-        long = "output-dir",
+        help_heading = "Download",
+        long,
         env,
-        help = "Output folder of all llamafile builds",
-        requires("build_llamafile"),
-        conflicts_with("llamafile_output")
+        default_value = "false",
+        help = "Don't check that downloads fit on the disk, with 1GiB to spare, before starting them"
     )]
-    llamafile_output_dir: Option<String>,
+    skip_disk_space_check: bool,
 
-    #[arg(long, env, help = "Path to zipalign")]
-    zipalign_path: Option<String>,
-}
+    #[arg(
+        help_heading = "Download",
+        long,
+        env,
+        default_value = "false",
+        help = "Download models again even when they're cached, e.g. after a truncated download, replacing the cached copy once complete"
+    )]
+    force_download: bool,
 
-#[derive(Debug, clap::Args)]
-#[group(required = true, multiple = true)]
-struct ModelSource {
     #[arg(
-        short = 'm',
+        help_heading = "Download",
         long,
-        requires("hf_file_name"),
         env,
-        help = "Hugging face repository"
+        help = "User-Agent sent with every HTTP request [default: llamafile-launcher/<version>]"
     )]
-    hf_model_name: Option<String>,
+    user_agent: Option<String>,
 
     #[arg(
-        short = 'n',
+        help_heading = "Download",
         long,
-        requires("hf_model_name"),
         env,
-        help = "Hugging face file name, within the repository"
+        default_value_t = 10,
+        help = "Maximum number of redirects followed by HTTP requests"
     )]
-    hf_file_name: Option<String>,
+    max_redirects: usize,
 
     #[arg(
-        short = 'f',
+        help_heading = "Download",
         long,
-        conflicts_with("hf_file_name"),
-        conflicts_with("hf_model_name"),
-        conflicts_with("file_url"),
         env,
-        help = "Local model file path"
+        default_value_t = 3,
+        help = "Retries of downloads failing with network or server errors, waiting 1s, 2s, 4s, .. in between"
     )]
-    file_path: Option<String>,
+    max_retries: u32,
 
     #[arg(
-        short = 'u',
+        help_heading = "Download",
         long,
-        conflicts_with("hf_file_name"),
-        conflicts_with("hf_model_name"),
-        conflicts_with("file_path"),
         env,
-        help = "Model URL"
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "Concurrent range requests per download, for servers that support them"
     )]
-    file_url: Option<String>,
-}
+    download_threads: u16,
 
-struct Runner {
-    llama_path: String,
-}
+    #[arg(
+        help_heading = "Download",
+        long,
+        env,
+        default_value_t = http_client::DEFAULT_TIMEOUT.as_secs(),
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Seconds to wait for a connection, or for more data of a download, before retrying"
+    )]
+    download_timeout: u64,
 
-impl Runner {
-    fn new(llama_path: String) -> Result<Self> {
-        if !Path::new(&llama_path).exists() {
-            return Err(anyhow::anyhow!(format!(
-                "Llama path '{}' does not exist",
-                llama_path
-            )));
-        }
+    #[arg(
+        help_heading = "Download",
+        long,
+        env,
+        help = "Read-through cache to download from first, as <cache-proxy>/<host>/<path>, falling back to the origin"
+    )]
+    cache_proxy: Option<String>,
 
-        Ok(Self { llama_path })
-    }
+    #[arg(
+        long,
+        env,
+        help = "Write a report of this run to the given file, whether it succeeds or fails"
+    )]
+    report: Option<String>,
 
-    async fn run(&self, model_path: &Path) -> Result<()> {
-        tokio::process::Command::new(&self.llama_path)
-            .arg("-m")
-            .arg(model_path)
-            .spawn()?
-            .wait()
-            .await?;
+    #[arg(
+        long,
+        env,
+        value_enum,
+        default_value_t,
+        help = "Format of the run report",
+        requires("report")
+    )]
+    report_format: report::ReportFormat,
 
-        Ok(())
-    }
-}
+    #[arg(
+        help_heading = "Run",
+        short = 'e',
+        long,
+        default_value = "false",
+        env,
+        help = "Execute the model"
+    )]
+    execute: bool,
 
-#[tokio::main]
-async fn main() {
-    env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
+    #[arg(
+        help_heading = "Run",
+        long,
+        env,
+        help = "Run a single completion of this prompt, print it and exit",
+        requires("execute"),
+        conflicts_with("gateway")
+    )]
+    prompt: Option<String>,
 
-    let args = Args::parse();
+    #[arg(
+        help_heading = "Run",
+        long,
+        env,
+        default_value_t = 128,
+        help = "Maximum number of tokens generated for --prompt"
+    )]
+    tokens: u32,
 
-    debug!("Args: {:?}", args);
+    #[arg(
+        help_heading = "Run",
+        long,
+        default_value = "false",
+        help = "Print the completion of --prompt as it is generated",
+        requires("prompt")
+    )]
+    stream: bool,
 
-    let mut model_path: Option<PathBuf> = None;
+    #[arg(
+        help_heading = "Run",
+        long,
+        env,
+        value_enum,
+        value_delimiter = ',',
+        help = "Artifacts to produce from the model, built concurrently"
+    )]
+    emit: Vec<Artifact>,
 
-    if let Some(file_path) = args.args.file_path {
-        let file_path = PathBuf::from(file_path);
-        if !file_path.exists() {
-            crash(&format!(
-                "File path '{}' does not exist",
-                file_path.display()
-            ));
-        }
-        model_path = Some(file_path);
-    } else {
-        info!("Initializing models directory");
-        let mut files = match Models::new(args.model_dir.clone()) {
-            Ok(files) => files,
-            Err(e) => crash(&format!("Failed to initialize models directory: {}", e)),
-        };
+    #[arg(
+        help_heading = "Run",
+        long,
+        env,
+        help = "Serve the executed model behind a bearer token gateway listening on this address",
+        requires("execute"),
+        requires("gateway_token")
+    )]
+    gateway: Option<std::net::SocketAddr>,
 
-        if let Some(model) = args.args.hf_model_name {
-            if let Some(filename) = args.args.hf_file_name {
-                let path = match files.get_hf_model(&model, &filename).await {
-                    Ok(path) => path,
-                    Err(e) => crash(&format!("Failed to get model: {}", e)),
-                };
+    #[arg(
+        help_heading = "Run",
+        long,
+        env,
+        help = "Bearer token required by the gateway",
+        requires("gateway")
+    )]
+    gateway_token: Option<http_client::Secret>,
 
-                model_path = Some(path);
-            }
-        } else if let Some(url) = args.args.file_url {
-            let path = match files.get_model(&url).await {
-                Ok(path) => path,
-                Err(e) => crash(&format!("Failed to get model: {}", e)),
-            };
+    #[arg(
+        help_heading = "Run",
+        long,
+        env,
+        default_value_t = 8081,
+        help = "Loopback port the model listens on behind the gateway"
+    )]
+    gateway_upstream_port: u16,
 
-            model_path = Some(path);
-        }
-    }
+    #[arg(
+        help_heading = "Run",
+        long,
+        env,
+        default_value = "false",
+        help = "Wait for the executed server to answer on /health and log its URL",
+        requires("execute"),
+        conflicts_with("gateway")
+    )]
+    wait_ready: bool,
 
-    info!("Located model");
-    let model_path = model_path.unwrap();
-    debug!("Model path: {:?}", model_path);
+    #[arg(
+        help_heading = "Run",
+        long,
+        env,
+        default_value_t = 300,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Seconds to wait for the server with --wait-ready, before stopping it"
+    )]
+    wait_ready_timeout: u64,
 
-    let llama_path = args
-        .llamafile_server_path
-        .clone()
-        .unwrap_or("./llamafile-server".to_string());
+    #[arg(
+        help_heading = "Run",
+        long,
+        env,
+        help = "Companion tokenizer embedded in built llamafiles next to the model, as a local path or a file of the Hugging Face repository, e.g. tokenizer.json"
+    )]
+    tokenizer: Option<String>,
 
-    let llama_path = Path::new(&llama_path);
-    let exists = llama_path.exists();
-    if !exists {
-        info!("Downloading llamafile-server");
-        let mut llamafile_builder = match LlamafileBuilder::new(None, None, None).await {
-            Ok(llamafile_builder) => llamafile_builder,
-            Err(e) => crash(&format!("Failed to initialize llamafile builder: {}", e)),
-        };
+    #[arg(
+        help_heading = "Run",
+        short = 'c',
+        long,
+        env,
+        help = "Context size in tokens, checked against the one the model was trained with"
+    )]
+    ctx_size: Option<u64>,
 
-        let download = llamafile_builder
-            .download_llamafile_github_release_into(
-                llamafile_builder::GithubReleaseAsset::LlamafileServer,
-                llama_path,
-            )
-            .await;
+    #[arg(
+        help_heading = "Run",
+        long,
+        env,
+        help = "Batch size for prompt processing"
+    )]
+    batch_size: Option<u64>,
 
-        if let Err(e) = download {
-            crash(&format!("Failed to download llamafile-server: {}", e));
-        }
-    }
-    info!("Using llamafile-server at {}", llama_path.display());
+    #[arg(
+        help_heading = "Run",
+        long,
+        env,
+        default_value = "false",
+        help = "Fail instead of warning when the context size exceeds the model's",
+        requires("ctx_size")
+    )]
+    strict: bool,
 
-    if args.docker_build {
-        info!("Building docker image");
-        let docker = match docker::Docker::new() {
-            Ok(docker) => docker,
-            Err(e) => crash(&format!("Failed to initialize docker: {}", e)),
-        };
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Print the resolved arguments as a command line and as TOML, then exit"
+    )]
+    print_config: bool,
 
-        let image_name = args.image_name.unwrap_or(
-            model_path
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string(),
-        );
+    /// Install the script where the shell loads completions from, e.g.
+    /// `launcher --generate-completions bash > ~/.local/share/bash-completion/completions/launcher`,
+    /// `launcher --generate-completions zsh > ~/.zfunc/_launcher` with `~/.zfunc` in `$fpath`,
+    /// or `launcher --generate-completions fish > ~/.config/fish/completions/launcher.fish`.
+    /// For PowerShell, add the script's output to `$PROFILE`.
+    #[arg(
+        long,
+        value_enum,
+        exclusive = true,
+        hide = true,
+        help = "Print the completion script of a shell, then exit"
+    )]
+    generate_completions: Option<clap_complete::Shell>,
 
-        match docker
-            .build_image(&image_name, vec![&model_path], llama_path)
-            .await
-        {
-            Ok(_) => info!("Built docker image"),
-            Err(e) => crash(&format!("Failed to build docker image: {}", e)),
-        }
-    }
+    #[arg(
+        long,
+        env,
+        default_value = "false",
+        help = "Print what would be downloaded, built and run, without doing it"
+    )]
+    dry_run: bool,
 
-    if args.build_args.build_llamafile {
-        info!("Building llamafile");
-        let mut llamafile_builder = match LlamafileBuilder::new(
-            args.build_args
-                .llamafile_output_dir
-                .as_ref()
-                .map(From::from),
-            args.llamafile_server_path.as_ref().map(From::from),
-            args.build_args.zipalign_path.as_ref().map(From::from),
-        )
-        .await
-        {
-            Ok(llamafile_builder) => llamafile_builder,
-            Err(e) => crash(&format!("Failed to initialize llamafile builder: {}", e)),
-        };
+    #[arg(
+        long,
+        env,
+        value_enum,
+        default_value_t,
+        alias = "output-format",
+        help = "Format of the dry-run plan. With json, a run also prints its result to stdout as \
+                a JSON object, logs staying on stderr"
+    )]
+    output: plan::OutputFormat,
 
-        let path: Option<PathBuf> = args.build_args.llamafile_output.as_ref().map(From::from);
+    #[arg(
+        long,
+        env,
+        default_value = "false",
+        help = "Print the equivalent llamafile-server and docker commands to stderr before running them"
+    )]
+    show_command: bool,
 
-        match llamafile_builder.build(&[&model_path], path).await {
-            Ok(_) => info!("Built llamafile"),
-            Err(e) => crash(&format!("Failed to build llamafile: {}", e)),
-        }
-    }
+    #[arg(
+        long,
+        env,
+        default_value = "false",
+        help = "Ask for confirmation before updating llamafile-server to a release with breaking changes"
+    )]
+    confirm_breaking_updates: bool,
 
-    if args.execute {
-        info!("Running the model");
+    #[arg(
+        help_heading = "Server",
+        short,
+        long,
+        env,
+        help = "Path to llamafile-server"
+    )]
+    llamafile_server_path: Option<String>,
 
-        let runner = match Runner::new(
-            args.llamafile_server_path
-                .unwrap_or("./llamafile-server".to_string()),
+    #[arg(
+        help_heading = "Server",
+        long,
+        env,
+        default_value = llamafile_builder::DEFAULT_HOST,
+        help = "Host the server listens on, with --execute and in built llamafiles. Docker images always listen on all interfaces"
+    )]
+    host: String,
+
+    #[arg(
+        help_heading = "Server",
+        long,
+        env,
+        default_value_t = llamafile_builder::DEFAULT_PORT,
+        help = "Port the server listens on, with --execute, in built llamafiles and docker images"
+    )]
+    port: u16,
+
+    #[arg(
+        help_heading = "Server",
+        long,
+        env,
+        help = "Download llamafile-server and zipalign from this release, e.g. 0.8.13 [default: latest]"
+    )]
+    llamafile_version: Option<String>,
+
+    #[arg(
+        help_heading = "Server",
+        long,
+        env,
+        help = "Name of the release asset llamafile-server is downloaded from [default: the one built for this platform, or the portable one]"
+    )]
+    llamafile_asset: Option<String>,
+
+    #[arg(
+        help_heading = "Docker",
+        short = 'b',
+        long,
+        env,
+        help = "Build docker image with the model",
+        default_value = "false"
+    )]
+    docker_build: bool,
+
+    #[arg(
+        help_heading = "Docker",
+        long,
+        env,
+        help = "Image name for the docker image"
+    )]
+    image_name: Option<String>,
+
+    #[arg(
+        help_heading = "Docker",
+        long,
+        env,
+        help = "Write a Compose file running the built docker image"
+    )]
+    compose_out: Option<String>,
+
+    #[arg(
+        help_heading = "Docker",
+        long,
+        env,
+        value_delimiter = ',',
+        help = "Patterns of files to exclude from the docker build context, in addition to partial downloads and hidden files"
+    )]
+    docker_exclude: Vec<String>,
+
+    #[arg(
+        help_heading = "Docker",
+        long,
+        env,
+        default_value = "false",
+        help = "Don't run the server under tini in the docker image"
+    )]
+    no_docker_init: bool,
+
+    #[arg(
+        help_heading = "Docker",
+        long,
+        env,
+        default_value_t = docker::DEFAULT_COMPRESSION,
+        value_parser = clap::value_parser!(u32).range(0..=9),
+        help = "Gzip level of the docker build context, from 0 to 9. 0 is fastest with a local daemon, higher levels send less data to a remote daemon but take more CPU"
+    )]
+    docker_compression: u32,
+
+    #[arg(
+        help_heading = "Docker",
+        long,
+        env,
+        help = "Base image of the docker image, which must provide apt-get and adduser like Debian and Ubuntu based images [default: debian:bullseye-slim, or nvidia/cuda:12.2.2-runtime-ubuntu22.04 with --docker-gpu]"
+    )]
+    docker_base_image: Option<String>,
+
+    #[arg(
+        help_heading = "Docker",
+        long,
+        env,
+        help = "Port the server listens on and the docker image exposes [default: --port]"
+    )]
+    docker_port: Option<u16>,
+
+    #[arg(
+        help_heading = "Docker",
+        long,
+        env,
+        default_value_t = docker::DEFAULT_UID,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "UID of the non-root user running the server in the docker image"
+    )]
+    docker_uid: u32,
+
+    #[arg(
+        help_heading = "Docker",
+        long,
+        env,
+        default_value_t = docker::DEFAULT_GID,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "GID of the non-root user running the server in the docker image"
+    )]
+    docker_gid: u32,
+
+    #[arg(
+        help_heading = "Docker",
+        long,
+        env,
+        help = "Dockerfile template used instead of the generated Dockerfile, with {{LLAMAFILE}}, {{MODEL}}, {{MODEL_<n>}}, {{MMPROJ}}, {{BASE_IMAGE}}, {{PORT}}, {{UID}} and {{GID}} placeholders"
+    )]
+    dockerfile: Option<String>,
+
+    #[arg(
+        help_heading = "Docker",
+        long,
+        env,
+        help = "Multimodal projector copied into the docker image, and passed to the server with --mmproj"
+    )]
+    docker_mmproj: Option<String>,
+
+    #[arg(
+        help_heading = "Docker",
+        long,
+        env,
+        default_value = "false",
+        help = "Build a docker image offloading the model to NVIDIA GPUs, run with `docker run --gpus all`"
+    )]
+    docker_gpu: bool,
+
+    #[arg(
+        help_heading = "Docker",
+        long,
+        env,
+        default_value_t = docker::DEFAULT_GPU_LAYERS,
+        help = "Number of layers offloaded to the GPU with --docker-gpu"
+    )]
+    docker_gpu_layers: u32,
+
+    #[arg(
+        help_heading = "Docker",
+        long,
+        env,
+        default_value = "false",
+        help = "Build the docker image from the base image's digest rather than its mutable tag"
+    )]
+    pin_base_digest: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Artifact {
+    Llamafile,
+    Docker,
+    Report,
+}
+
+/// Subcommands either select what a run does, taking the model source and the other flags
+/// before them, e.g. `launcher -m <repo> docker`, or are standalone tools.
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Download the model and llamafile-server, the default without a subcommand
+    Download,
+    /// Build a llamafile embedding the model, like --build-llamafile
+    BuildLlamafile,
+    /// Run the model, like --execute
+    Run,
+    /// Build a docker image serving the model, like --docker-build
+    Docker,
+    /// Build the models declared in a YAML spec
+    Apply(apply::ApplyArgs),
+    /// Time model loading and token throughput
+    Bench(bench::BenchArgs),
+    /// Print where models are cached, exiting with a non-zero code if any isn't
+    Cached(ModelSource),
+    /// Generate a systemd unit running a model
+    Systemd(systemd::SystemdArgs),
+    /// Annotate a cached model with a note and tags
+    Tag(TagArgs),
+    /// List cached models with their size, including interrupted downloads
+    List(ListArgs),
+    /// Delete cached models
+    Remove(RemoveArgs),
+    /// Delete cached models that weren't modified recently
+    Prune(PruneArgs),
+    /// Move cached models into another layout
+    MigrateCache(MigrateCacheArgs),
+    /// Convert a GGUF file to another quantization
+    Quantize(quantize::QuantizeArgs),
+    /// Check cached models against their checksums
+    Verify(VerifyArgs),
+}
+
+impl Command {
+    /// Whether the subcommand selects what the run does, rather than being a standalone tool.
+    fn is_workflow(&self) -> bool {
+        matches!(
+            self,
+            Command::Download | Command::BuildLlamafile | Command::Run | Command::Docker
+        )
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct VerifyArgs {
+    #[arg(
+        long,
+        help = "Number of files hashed concurrently [default: number of CPUs]"
+    )]
+    verify_jobs: Option<usize>,
+}
+
+#[derive(Debug, clap::Args)]
+struct MigrateCacheArgs {
+    #[arg(long, value_enum, help = "Layout to migrate to")]
+    to: models::Layout,
+
+    #[arg(long, default_value = "false", help = "Only print what would be moved")]
+    dry_run: bool,
+}
+
+#[derive(Debug, clap::Args)]
+struct TagArgs {
+    #[arg(help = "Cached model, as a path or relative to the models directory")]
+    model: String,
+
+    #[arg(long, help = "Note about the model")]
+    note: Option<String>,
+
+    #[arg(long = "tag", help = "Tag to add to the model")]
+    tags: Vec<String>,
+
+    #[arg(long = "untag", help = "Tag to remove from the model")]
+    untags: Vec<String>,
+}
+
+#[derive(Debug, clap::Args)]
+struct RemoveArgs {
+    #[arg(
+        required = true,
+        help = "Cached models, as paths or relative to the models directory"
+    )]
+    models: Vec<String>,
+}
+
+#[derive(Debug, clap::Args)]
+struct PruneArgs {
+    #[arg(
+        long,
+        help = "Delete models last modified more than this many days ago"
+    )]
+    older_than: u64,
+
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Only print what would be deleted"
+    )]
+    dry_run: bool,
+}
+
+#[derive(Debug, clap::Args)]
+struct ListArgs {
+    #[arg(long, help = "Only list models with this tag")]
+    tag: Option<String>,
+}
+
+#[derive(Debug, clap::Args)]
+#[group(required = false, multiple = true)]
+#[command(next_help_heading = "Llamafile")]
+struct BuildArgs {
+    #[arg(
+        short = 'B',
+        long,
+        env,
+        help = "Build llamafile with embedded model",
+        default_value = "false"
+    )]
+    build_llamafile: bool,
+
+    #[arg(
+        short = 'o',
+        long,
+        env,
+        help = "Output file of llamafile build",
+        conflicts_with("llamafile_output_dir")
+    )]
+    llamafile_output: Option<String>,
+
+    #[arg(
+        long = "output-dir",
+        env,
+        help = "Output folder of all llamafile builds",
+        conflicts_with("llamafile_output")
+    )]
+    llamafile_output_dir: Option<String>,
+
+    #[arg(long, env, help = "Path to zipalign")]
+    zipalign_path: Option<String>,
+
+    #[arg(
+        long,
+        env,
+        help = "Directory for intermediate build files, e.g. on a fast disk [default: system temporary directory]"
+    )]
+    work_dir: Option<String>,
+
+    #[arg(
+        long,
+        env,
+        default_value = "false",
+        help = "Keep the temporary directory of the build, for debugging"
+    )]
+    keep_temp: bool,
+
+    #[arg(
+        long,
+        env,
+        default_value = "false",
+        help = "Start the built llamafile and wait for its server to be healthy"
+    )]
+    smoke_test: bool,
+
+    #[arg(
+        long,
+        env,
+        default_value_t = 0,
+        help = "Embedded model served by default, the others being selected with `-m <name>`. Also selects the model served by the docker image"
+    )]
+    default_model_index: usize,
+
+    #[arg(
+        long = "llamafile-arg",
+        allow_hyphen_values = true,
+        help = "Argument baked into the llamafile's .args, e.g. --llamafile-arg=-ngl --llamafile-arg=999. Repeatable"
+    )]
+    llamafile_args: Vec<String>,
+
+    #[arg(
+        long,
+        env,
+        help = "Embed the model's LICENSE into the llamafile",
+        default_value = "false"
+    )]
+    embed_license: bool,
+
+    #[arg(
+        long,
+        env,
+        help = "Local LICENSE file to embed, instead of fetching it from Hugging Face",
+        requires("embed_license")
+    )]
+    license_file: Option<String>,
+
+    #[arg(
+        long,
+        env,
+        help = "Embed the model's README.md (model card) into the llamafile",
+        default_value = "false"
+    )]
+    embed_readme: bool,
+
+    #[arg(
+        long,
+        env,
+        help = "Local README file to embed, instead of fetching it from Hugging Face",
+        requires("embed_readme")
+    )]
+    readme_file: Option<String>,
+}
+
+#[derive(Debug, clap::Args)]
+#[group(required = true, multiple = true)]
+#[command(next_help_heading = "Model source")]
+struct ModelSource {
+    #[arg(
+        short = 'm',
+        long,
+        env,
+        help = "Hugging face repository. If no file name is given, its only GGUF file is used. Repeatable"
+    )]
+    hf_model_name: Vec<String>,
+
+    #[arg(
+        short = 'n',
+        long,
+        requires("hf_model_name"),
+        env,
+        help = "Hugging face file name, within the repository. Repeatable, once per repository or all within the only one"
+    )]
+    hf_file_name: Vec<String>,
+
+    #[arg(
+        long,
+        requires("hf_model_name"),
+        env,
+        help = "Branch, tag or commit of the Hugging Face repository [default: main]"
+    )]
+    hf_revision: Option<String>,
+
+    #[arg(short = 'f', long, env, help = "Local model file path. Repeatable")]
+    file_path: Vec<String>,
+
+    #[arg(short = 'u', long, env, help = "Model URL. Repeatable")]
+    file_url: Vec<String>,
+
+    #[arg(
+        long,
+        conflicts_with_all(["hf_file_name", "hf_model_name", "file_path", "file_url"]),
+        env,
+        help = "URL of a JSON document describing the model: {\"url\", \"name\", \"sha256\", \"size\"}, where only url is required"
+    )]
+    manifest_url: Option<String>,
+}
+
+/// One of the models given on the command line.
+enum Source<'a> {
+    File(&'a str),
+    Hf {
+        model: &'a str,
+        filename: Option<&'a str>,
+    },
+    Url(&'a str),
+    Manifest(&'a str),
+}
+
+impl ModelSource {
+    /// The models given, file paths first, then Hugging Face files and URLs. The first one is
+    /// the primary model, naming the outputs. File names are paired with repositories in order,
+    /// or all belong to the only repository.
+    fn sources(&self) -> anyhow::Result<Vec<Source<'_>>> {
+        let mut sources: Vec<Source> = self
+            .file_path
+            .iter()
+            .map(|path| Source::File(path))
+            .collect();
+        match (self.hf_model_name.as_slice(), self.hf_file_name.as_slice()) {
+            (models, []) => sources.extend(models.iter().map(|model| Source::Hf {
+                model,
+                filename: None,
+            })),
+            ([model], filenames) => {
+                sources.extend(filenames.iter().map(|filename| Source::Hf {
+                    model,
+                    filename: Some(filename),
+                }))
+            }
+            (models, filenames) if models.len() == filenames.len() => {
+                sources.extend(models.iter().zip(filenames).map(|(model, filename)| {
+                    Source::Hf {
+                        model,
+                        filename: Some(filename),
+                    }
+                }))
+            }
+            (models, filenames) => anyhow::bail!(
+                "Got {} Hugging Face repositories and {} file names, give a file name per repository",
+                models.len(),
+                filenames.len()
+            ),
+        }
+        sources.extend(self.file_url.iter().map(|url| Source::Url(url)));
+        sources.extend(self.manifest_url.iter().map(|url| Source::Manifest(url)));
+
+        // Subcommands lift clap's requirement of a model source, which the workflow ones need.
+        if sources.is_empty() {
+            anyhow::bail!(
+                "No model source given, pass e.g. --hf-model-name, --file-path or --file-url before the subcommand"
+            );
+        }
+        Ok(sources)
+    }
+}
+
+impl Source<'_> {
+    /// Where the model comes from, for reports.
+    fn describe(&self, hf_revision: Option<&str>) -> String {
+        match self {
+            Self::File(path) => path.to_string(),
+            Self::Hf { model, filename } => format!(
+                "hf:{}{}/{}",
+                model,
+                hf_revision
+                    .map(|revision| format!("@{}", revision))
+                    .unwrap_or_default(),
+                filename.unwrap_or("")
+            ),
+            Self::Url(url) | Self::Manifest(url) => url.to_string(),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let (mut args, matches, config_file) = parse_args();
+
+    let default_filter = if args.quiet { "warn" } else { "info" };
+    let mut logger =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_filter));
+    if args.quiet_success {
+        logger.target(env_logger::Target::Pipe(log_buffer::writer()));
+    }
+    logger.init();
+
+    debug!("Args: {:?}", args);
+
+    if let Some(shell) = args.generate_completions {
+        let mut command = Args::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        return;
+    }
+
+    if args.print_config {
+        config::print(&Args::command(), &matches);
+        return;
+    }
+
+    output::set_overwrite(args.overwrite);
+
+    let mut header_rules = config_file.headers;
+    // After the configured rules, which take precedence as only the first matching rule applies.
+    if let Some(hf_token) = args.hf_token.as_ref() {
+        header_rules.push(http_client::HeaderRule {
+            prefix: format!("{}/", models::HF_ENDPOINT),
+            headers: [(
+                "Authorization".to_string(),
+                format!("Bearer {}", hf_token.0),
+            )]
+            .into(),
+        });
+    }
+
+    let mut http_config = http_client::HttpConfig {
+        max_redirects: args.max_redirects,
+        max_retries: args.max_retries,
+        download_threads: args.download_threads.into(),
+        timeout: Duration::from_secs(args.download_timeout),
+        cache_proxy: args.cache_proxy.clone(),
+        // Progress bars are drawn on stderr, and only clutter it when it's logged.
+        no_progress: args.no_progress || args.quiet || !std::io::stderr().is_terminal(),
+        header_rules,
+        ..Default::default()
+    };
+    if let Some(user_agent) = args.user_agent.clone() {
+        http_config.user_agent = user_agent;
+    }
+    http_client::configure(http_config);
+
+    match args.command {
+        Some(Command::BuildLlamafile) => args.build_args.build_llamafile = true,
+        Some(Command::Run) => args.execute = true,
+        Some(Command::Docker) => args.docker_build = true,
+        _ => {}
+    }
+
+    if let Some(command) = args
+        .command
+        .as_ref()
+        .filter(|command| !command.is_workflow())
+    {
+        match command {
+            Command::Download | Command::BuildLlamafile | Command::Run | Command::Docker => {
+                unreachable!()
+            }
+            Command::Bench(bench_args) => {
+                let runner = match Runner::new(
+                    args.llamafile_server_path
+                        .clone()
+                        .unwrap_or_else(default_llamafile_server_path),
+                ) {
+                    Ok(runner) => runner,
+                    Err(e) => crash(&format!("Failed to initialize llama: {}", e)),
+                };
+
+                if let Err(e) = bench::bench(&runner, bench_args).await {
+                    crash(&format!("Benchmark failed: {}", e));
+                }
+            }
+            Command::Cached(source) => match cached_model_paths(&args, source) {
+                Some(paths) => paths.iter().for_each(|path| println!("{}", path.display())),
+                None => exit(1),
+            },
+            Command::Systemd(systemd_args) => {
+                let llama_path = args
+                    .llamafile_server_path
+                    .clone()
+                    .unwrap_or_else(default_llamafile_server_path);
+
+                if let Err(e) = systemd::generate(Path::new(&llama_path), systemd_args) {
+                    crash(&format!("Failed to generate systemd unit: {}", e));
+                }
+            }
+            Command::Tag(tag_args) => {
+                if let Err(e) = tag_model(&args, tag_args) {
+                    crash(&format!("Failed to tag model: {}", e));
+                }
+            }
+            Command::List(list_args) => {
+                if let Err(e) = list_models(&args, list_args) {
+                    crash(&format!("Failed to list models: {}", e));
+                }
+            }
+            Command::Remove(remove_args) => {
+                let removed = open_models(&args).and_then(|files| {
+                    remove_args
+                        .models
+                        .iter()
+                        .try_for_each(|model| files.remove(model))
+                });
+                if let Err(e) = removed {
+                    crash(&format!("Failed to remove model: {}", e));
+                }
+            }
+            Command::Prune(prune_args) => {
+                let older_than = Duration::from_secs(prune_args.older_than * 24 * 60 * 60);
+                let pruned = open_models(&args)
+                    .and_then(|files| files.prune(older_than, prune_args.dry_run));
+                if let Err(e) = pruned {
+                    crash(&format!("Failed to prune models: {}", e));
+                }
+            }
+            Command::MigrateCache(migrate_args) => {
+                let migrated = open_models(&args)
+                    .and_then(|files| files.migrate(migrate_args.to, migrate_args.dry_run));
+                if let Err(e) = migrated {
+                    crash(&format!("Failed to migrate models: {}", e));
+                }
+            }
+            Command::Verify(verify_args) => {
+                let jobs = verify_args.verify_jobs.unwrap_or_else(|| {
+                    std::thread::available_parallelism().map_or(1, |jobs| jobs.get())
+                });
+                let verified = match open_models(&args) {
+                    Ok(mut files) => files.verify(jobs).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = verified {
+                    crash(&format!("Failed to verify models: {}", e));
+                }
+            }
+            Command::Apply(apply_args) => {
+                if let Err(e) = apply_spec(&args, apply_args).await {
+                    crash(&format!("Failed to apply spec: {:#}", e));
+                }
+            }
+            Command::Quantize(quantize_args) => {
+                if let Err(e) = quantize::quantize(quantize_args).await {
+                    crash(&format!("Failed to quantize model: {:#}", e));
+                }
+            }
+        }
+
+        return;
+    }
+
+    if args.report.is_none() && args.emit.contains(&Artifact::Report) {
+        crash("--emit report requires --report <path>");
+    }
+    let print_result = matches!(args.output, plan::OutputFormat::Json);
+    if args.report.is_some() || print_result {
+        report::init(
+            args.report.as_ref().map(PathBuf::from),
+            args.report_format,
+            print_result,
+        );
+    }
+
+    let model_source = match args.args.sources() {
+        Ok(sources) => sources
+            .iter()
+            .map(|source| source.describe(args.args.hf_revision.as_deref()))
+            .collect::<Vec<_>>()
+            .join(", "),
+        Err(e) => crash(&format!("{:#}", e)),
+    };
+    report::update(|report| report.model_source = Some(model_source));
+
+    let llama_path = args
+        .llamafile_server_path
+        .clone()
+        .unwrap_or_else(default_llamafile_server_path);
+    let llama_path = Path::new(&llama_path);
+
+    if args.dry_run {
+        let plan = plan_run(&args, llama_path).await;
+        if let Err(e) = plan.and_then(|plan| plan.print(args.output)) {
+            crash(&format!("Failed to plan the run: {:#}", e));
+        }
+        return;
+    }
+
+    // The model and llamafile-server are independent downloads, so they can run concurrently.
+    // The first failure drops the other download, which is resumed by the next run.
+    let located = tokio::try_join!(
+        locate_model(&args),
+        locate_llamafile_server(&args, llama_path)
+    );
+    let (model_paths, mut embedded_files, tokenizer) = match located {
+        Ok((located_model, ())) => located_model,
+        Err(e) => crash(&format!("{:#}", e)),
+    };
+    let Some(model_path) = model_paths
+        .get(args.build_args.default_model_index)
+        .cloned()
+    else {
+        crash(&format!(
+            "Default model index {} is out of range, only {} models are given",
+            args.build_args.default_model_index,
+            model_paths.len()
+        ));
+    };
+
+    if let Some(ctx_size) = args.ctx_size {
+        for model_path in &model_paths {
+            if let Err(e) = gguf::validate_context_size(model_path, ctx_size, args.strict) {
+                crash(&format!("Invalid context size: {}", e));
+            }
+        }
+    }
+
+    for local_file in [&args.build_args.license_file, &args.build_args.readme_file]
+        .into_iter()
+        .flatten()
+    {
+        let local_file = PathBuf::from(local_file);
+        if !local_file.exists() {
+            crash(&format!(
+                "File path '{}' does not exist",
+                local_file.display()
+            ));
+        }
+        embedded_files.push(local_file);
+    }
+
+    if args.build_args.embed_license
+        && args.build_args.license_file.is_none()
+        && args.args.hf_model_name.is_empty()
+    {
+        warn!("--embed-license requires a Hugging Face model or --license-file, skipping");
+    }
+
+    if args.build_args.embed_readme
+        && args.build_args.readme_file.is_none()
+        && args.args.hf_model_name.is_empty()
+    {
+        warn!("--embed-readme requires a Hugging Face model or --readme-file, skipping");
+    }
+
+    let emit_docker = args.docker_build || args.emit.contains(&Artifact::Docker);
+    let emit_llamafile =
+        args.build_args.build_llamafile || args.emit.contains(&Artifact::Llamafile);
+    embedded_files.extend(tokenizer);
+    let embedded_files: Vec<&Path> = embedded_files.iter().map(PathBuf::as_path).collect();
+
+    // Both builds only read the model and llamafile-server, so they can run concurrently.
+    let (docker_image, llamafile) = tokio::join!(
+        async {
+            if emit_docker {
+                Some(build_docker_image(&args, &model_paths, llama_path).await)
+            } else {
+                None
+            }
+        },
+        async {
+            if emit_llamafile {
+                Some(build_llamafile(&args, &model_paths, llama_path, &embedded_files).await)
+            } else {
+                None
+            }
+        }
+    );
+
+    match docker_image {
+        Some(Ok(image_name)) => {
+            report::update(|report| report.docker_image = Some(image_name));
+        }
+        Some(Err(e)) => crash(&format!("Failed to build docker image: {:#}", e)),
+        None => {}
+    }
+
+    match llamafile {
+        Some(Ok(output)) => {
+            report::update(|report| report.llamafile_output = Some(output));
+        }
+        Some(Err(e)) => crash(&format!("Failed to build llamafile: {:#}", e)),
+        None => {}
+    }
+
+    if args.execute {
+        info!("Running the model");
+        let started = Instant::now();
+
+        let mut runner = match Runner::new(
+            args.llamafile_server_path
+                .clone()
+                .unwrap_or_else(default_llamafile_server_path),
         ) {
             Ok(runner) => runner,
             Err(e) => crash(&format!("Failed to initialize llama: {}", e)),
         };
+        runner.set_server_args(run_args(&args));
+        if args.wait_ready {
+            // A server listening on all interfaces is reached on the loopback one.
+            let host = match args.host.as_str() {
+                "0.0.0.0" => "127.0.0.1".to_string(),
+                "::" => "[::1]".to_string(),
+                host if host.contains(':') => format!("[{}]", host),
+                host => host.to_string(),
+            };
+            runner.set_wait_ready(
+                format!("http://{}:{}", host, args.port),
+                Duration::from_secs(args.wait_ready_timeout),
+            );
+        }
+
+        if args.show_command {
+            let command = match args.gateway {
+                Some(_) => {
+                    gateway::server_command(&runner, &model_path, args.gateway_upstream_port)
+                }
+                None => runner.command(&model_path),
+            };
+            eprintln!("{}", runner::command_line(&command));
+        }
+
+        if let Some(prompt) = args.prompt.as_deref() {
+            match completion::complete(&runner, &model_path, prompt, args.tokens, args.stream).await
+            {
+                Ok(_) => report::record_timing("completion", started),
+                Err(e) => crash(&format!("Completion failed: {}", e)),
+            }
+            http_client::log_retry_summary();
+            report::write(report::Status::Success, None);
+            return;
+        }
+
+        let run = match (args.gateway, args.gateway_token.as_ref()) {
+            (Some(listen), Some(token)) => {
+                gateway::run(
+                    &runner,
+                    &model_path,
+                    listen,
+                    args.gateway_upstream_port,
+                    &token.0,
+                )
+                .await
+            }
+            _ => runner.run(&model_path).await,
+        };
 
-        match runner.run(&model_path).await {
+        match run {
             Ok(_) => info!("Llama exited successfully"),
             Err(e) => crash(&format!("Llama exited with error: {}", e)),
         };
+        report::record_timing("execute", started);
+    }
+
+    http_client::log_retry_summary();
+    report::write(report::Status::Success, None);
+}
+
+/// Finds or downloads the models, along with the files to embed next to them and the companion
+/// tokenizer, which come from the first Hugging Face repository.
+async fn locate_model(
+    args: &Args,
+) -> anyhow::Result<(Vec<PathBuf>, Vec<PathBuf>, Option<PathBuf>)> {
+    let started = Instant::now();
+    let mut embedded_files: Vec<PathBuf> = Vec::new();
+    let mut tokenizer = args
+        .tokenizer
+        .as_ref()
+        .map(PathBuf::from)
+        .filter(|path| path.exists());
+
+    let sources = args.args.sources()?;
+    let mut files: Option<Models> = None;
+    let mut model_paths = Vec::new();
+    for source in &sources {
+        if let Source::File(file_path) = source {
+            let file_path = PathBuf::from(file_path);
+            if !file_path.exists() {
+                anyhow::bail!("File path '{}' does not exist", file_path.display());
+            }
+            model_paths.push(file_path);
+            continue;
+        }
+
+        let files = match files.as_mut() {
+            Some(files) => files,
+            None => {
+                info!("Initializing models directory");
+                files.insert(open_models(args).context("Failed to initialize models directory")?)
+            }
+        };
+        model_paths.push(locate_source(files, source).await?);
+    }
+
+    let first_repo = sources.iter().find_map(|source| match source {
+        Source::Hf { model, .. } => Some(*model),
+        _ => None,
+    });
+    if let (Some(model), Some(files)) = (first_repo, files.as_mut()) {
+        let build_args = &args.build_args;
+        if build_args.embed_license && build_args.license_file.is_none() {
+            match files
+                .get_hf_file_if_exists(model, "LICENSE")
+                .await
+                .context("Failed to get LICENSE")?
+            {
+                Some(path) => embedded_files.push(path),
+                None => warn!("Repository {} has no LICENSE file", model),
+            }
+        }
+
+        if build_args.embed_readme && build_args.readme_file.is_none() {
+            match files
+                .get_hf_file_if_exists(model, "README.md")
+                .await
+                .context("Failed to get README.md")?
+            {
+                Some(path) => embedded_files.push(path),
+                None => warn!("Repository {} has no README.md file", model),
+            }
+        }
+
+        if let (Some(filename), None) = (args.tokenizer.as_ref(), tokenizer.as_ref()) {
+            tokenizer = files
+                .get_hf_file_if_exists(model, filename)
+                .await
+                .context("Failed to get tokenizer")?;
+        }
+    }
+
+    if let (Some(filename), None) = (args.tokenizer.as_ref(), tokenizer.as_ref()) {
+        anyhow::bail!(
+            "Tokenizer '{}' is neither a local file nor in the model's repository",
+            filename
+        );
+    }
+
+    // A sharded model is located by any of its shards, and loaded from the first.
+    let model_paths = model_paths
+        .iter()
+        .map(|model_path| Ok(models::shard_paths(model_path)?.swap_remove(0)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    info!("Located model");
+    debug!("Model paths: {:?}", model_paths);
+    report::record_timing("locate model", started);
+    report::update(|report| {
+        report.model_size = std::fs::metadata(&model_paths[0]).map(|m| m.len()).ok();
+        report.model_path = Some(model_paths[0].clone());
+    });
+
+    Ok((model_paths, embedded_files, tokenizer))
+}
+
+/// Finds or downloads a model that isn't a local file.
+async fn locate_source(files: &mut Models, source: &Source<'_>) -> anyhow::Result<PathBuf> {
+    match *source {
+        Source::File(file_path) => Ok(PathBuf::from(file_path)),
+        Source::Hf { model, filename } => {
+            let filename = match filename {
+                Some(filename) => filename.to_string(),
+                None => files
+                    .find_hf_gguf(model)
+                    .await
+                    .context("Failed to find model file")?,
+            };
+            files
+                .get_hf_model_shards(model, &filename)
+                .await
+                .context("Failed to get model")
+        }
+        Source::Url(url) => files.get_model(url).await.context("Failed to get model"),
+        Source::Manifest(manifest_url) => files
+            .get_manifest_model(manifest_url)
+            .await
+            .context("Failed to get model"),
+    }
+}
+
+/// Resolves what the run would do, reading remote metadata but downloading, building and
+/// running nothing.
+async fn plan_run(args: &Args, llama_path: &Path) -> anyhow::Result<plan::Plan> {
+    let mut plan = plan::Plan::default();
+
+    let mut files: Option<Models> = None;
+    let mut model_paths = Vec::new();
+    for source in args.args.sources()? {
+        let (model_path, download, estimated_bytes, input) = match source {
+            Source::File(file_path) => plan_file(file_path),
+            source => {
+                let files = match files.as_mut() {
+                    Some(files) => files,
+                    None => files.insert(open_models(args)?),
+                };
+                plan_source(files, &source).await?
+            }
+        };
+
+        plan.push(plan::Step {
+            kind: plan::StepKind::LocateModel,
+            inputs: vec![input],
+            outputs: vec![model_path.display().to_string()],
+            download,
+            estimated_bytes,
+            command: None,
+        });
+        model_paths.push(model_path);
+    }
+    let model_path = &model_paths[0];
+    let model_inputs: Vec<String> = model_paths
+        .iter()
+        .map(|model_path| model_path.display().to_string())
+        .collect();
+
+    let llama_exists = llama_path.exists();
+    plan.push(plan::Step {
+        kind: plan::StepKind::LocateLlamafileServer,
+        inputs: vec![if llama_exists {
+            llama_path.display().to_string()
+        } else {
+            match args.llamafile_version.as_ref() {
+                Some(version) => format!("llamafile release {}", version),
+                None => "latest llamafile release".to_string(),
+            }
+        }],
+        outputs: vec![llama_path.display().to_string()],
+        download: !llama_exists,
+        estimated_bytes: None,
+        command: None,
+    });
+
+    if args.build_args.build_llamafile || args.emit.contains(&Artifact::Llamafile) {
+        let output = match (
+            &args.build_args.llamafile_output,
+            &args.build_args.llamafile_output_dir,
+        ) {
+            (Some(output), _) => PathBuf::from(output),
+            (None, Some(output_dir)) => {
+                Path::new(output_dir).join(llamafile_builder::output_file_name(model_path))
+            }
+            (None, None) => anyhow::bail!("Neither an output file nor directory were specified"),
+        };
+        let mut zipalign = vec![
+            "zipalign".to_string(),
+            "-j0".to_string(),
+            output.display().to_string(),
+        ];
+        zipalign.extend(model_inputs.iter().cloned());
+        zipalign.push(".args".to_string());
+        let zipalign = zipalign
+            .iter()
+            .map(|arg| runner::shell_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+        plan.push(plan::Step {
+            kind: plan::StepKind::BuildLlamafile,
+            inputs: std::iter::once(llama_path.display().to_string())
+                .chain(model_inputs.iter().cloned())
+                .collect(),
+            outputs: vec![output.display().to_string()],
+            download: false,
+            estimated_bytes: None,
+            command: Some(zipalign),
+        });
+    }
+
+    if args.docker_build || args.emit.contains(&Artifact::Docker) {
+        let image_name = args.image_name.clone().unwrap_or(
+            model_path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        );
+        let base_image = match (&args.docker_base_image, args.docker_gpu) {
+            (Some(base_image), _) => base_image.clone(),
+            (None, true) => docker::DEFAULT_GPU_BASE_IMAGE.to_string(),
+            (None, false) => docker::DEFAULT_BASE_IMAGE.to_string(),
+        };
+        let mut outputs = vec![image_name.clone()];
+        outputs.extend(args.compose_out.clone());
+        plan.push(plan::Step {
+            kind: plan::StepKind::BuildDocker,
+            inputs: [base_image, llama_path.display().to_string()]
+                .into_iter()
+                .chain(model_inputs.iter().cloned())
+                .collect(),
+            outputs,
+            download: false,
+            estimated_bytes: None,
+            command: Some(format!(
+                "docker build -t {} -",
+                runner::shell_quote(&image_name)
+            )),
+        });
+    }
+
+    if args.execute {
+        let served = model_paths.get(args.build_args.default_model_index);
+        let command = served.map(|model_path| {
+            let mut command = tokio::process::Command::new(llama_path);
+            if llamafile_builder::needs_server_flag(llama_path) {
+                command.arg("--server");
+            }
+            command.arg("-m").arg(model_path).args(run_args(args));
+            runner::command_line(&command)
+        });
+        plan.push(plan::Step {
+            kind: plan::StepKind::Run,
+            inputs: served
+                .map(|model_path| vec![model_path.display().to_string()])
+                .unwrap_or_default(),
+            outputs: Vec::new(),
+            download: false,
+            estimated_bytes: None,
+            command,
+        });
+    }
+
+    Ok(plan)
+}
+
+fn plan_file(file_path: &str) -> (PathBuf, bool, Option<u64>, String) {
+    let size = std::fs::metadata(file_path).map(|m| m.len()).ok();
+    (PathBuf::from(file_path), false, size, file_path.to_string())
+}
+
+/// Resolves where a model is cached, and whether it needs downloading.
+async fn plan_source(
+    files: &mut Models,
+    source: &Source<'_>,
+) -> anyhow::Result<(PathBuf, bool, Option<u64>, String)> {
+    match *source {
+        Source::File(file_path) => Ok(plan_file(file_path)),
+        Source::Hf { model, filename } => {
+            let filename = match filename {
+                Some(filename) => filename.to_string(),
+                None => files.find_hf_gguf(model).await?,
+            };
+            let url = files.hf_url(model, &filename);
+            match files.cached_hf_model(model, &filename) {
+                Some(path) => {
+                    let size = std::fs::metadata(&path).map(|m| m.len()).ok();
+                    Ok((path, false, size, url))
+                }
+                None => {
+                    let size = files.remote_size(&url).await?;
+                    let path = files.hf_path(model, &filename);
+                    Ok((path, true, size, url))
+                }
+            }
+        }
+        Source::Url(url) => match files.cached_model(url)? {
+            Some(path) => {
+                let size = std::fs::metadata(&path).map(|m| m.len()).ok();
+                Ok((path, false, size, url.to_string()))
+            }
+            None => {
+                let size = files.remote_size(url).await?;
+                let path = files.url_path(url);
+                Ok((path, true, size, url.to_string()))
+            }
+        },
+        Source::Manifest(manifest_url) => {
+            let descriptor = files.model_descriptor(manifest_url).await?;
+            let path = files.base_dir().join(descriptor.filename(manifest_url)?);
+            let download = !path.exists();
+            Ok((path, download, descriptor.size, descriptor.url.clone()))
+        }
+    }
+}
+
+/// Downloads llamafile-server to `llama_path` if it's missing, or updates it if it was
+/// downloaded from a release.
+async fn locate_llamafile_server(args: &Args, llama_path: &Path) -> anyhow::Result<()> {
+    let exists = llama_path.exists();
+    let started = Instant::now();
+    if !exists || llamafile_builder::is_release_download(llama_path) {
+        if exists {
+            info!("Checking for llamafile-server updates");
+        } else {
+            info!("Downloading llamafile-server");
+        }
+        let mut llamafile_builder = LlamafileBuilder::new(None, None, None, None)
+            .await
+            .context("Failed to initialize llamafile builder")?;
+        llamafile_builder.set_confirm_breaking_updates(args.confirm_breaking_updates);
+        llamafile_builder.set_release_version(args.llamafile_version.clone());
+        llamafile_builder.set_server_asset(args.llamafile_asset.clone());
+
+        llamafile_builder
+            .download_llamafile_github_release_into(
+                llamafile_builder::GithubReleaseAsset::LlamafileServer,
+                llama_path,
+            )
+            .await
+            .context("Failed to download llamafile-server")?;
+    }
+    info!("Using llamafile-server at {}", llama_path.display());
+    report::record_timing("locate llamafile-server", started);
+    report::update(|report| report.llamafile_server_path = Some(llama_path.to_path_buf()));
+
+    Ok(())
+}
+
+async fn build_docker_image(
+    args: &Args,
+    model_paths: &[PathBuf],
+    llama_path: &Path,
+) -> anyhow::Result<String> {
+    info!("Building docker image");
+    let started = Instant::now();
+    let mut docker = docker::Docker::new(args.docker_exclude.clone(), !args.no_docker_init)
+        .context("Failed to initialize docker")?;
+    docker.set_server_args(server_args(args));
+    docker.set_compression(args.docker_compression);
+    let port = args.docker_port.unwrap_or(args.port);
+    docker.set_port(port);
+    docker.set_user(args.docker_uid, args.docker_gid)?;
+    if let Some(base_image) = args.docker_base_image.as_ref() {
+        docker.set_base_image(base_image.clone());
+    }
+    if args.docker_gpu {
+        docker.set_gpu_layers(args.docker_gpu_layers);
+    }
+    let (shards, default_model_index) =
+        expand_shards(model_paths, args.build_args.default_model_index)?;
+    docker.set_default_model_index(default_model_index);
+    docker.set_projector(args.docker_mmproj.as_ref().map(PathBuf::from));
+    if let Some(dockerfile) = args.dockerfile.as_ref() {
+        let template = std::fs::read_to_string(dockerfile)
+            .with_context(|| format!("Failed to read {}", dockerfile))?;
+        docker.set_dockerfile_template(Some(template));
+    }
+    let base_image = docker.base_image().to_string();
+
+    if args.pin_base_digest {
+        let pinned = docker
+            .pin_base_image()
+            .await
+            .context("Failed to pin the base image")?;
+
+        let models = open_models(args)?;
+        let mut manifest = Manifest::load(models.base_dir())?;
+        manifest.base_images.insert(base_image, pinned);
+        manifest.save()?;
+    }
+    report::update(|report| report.docker_base_image = Some(docker.base_image().to_string()));
+
+    let image_name = args.image_name.clone().unwrap_or(
+        model_paths[0]
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string(),
+    );
+
+    if args.show_command {
+        for command in docker.equivalent_commands(&image_name) {
+            eprintln!("{}", command);
+        }
+    }
+
+    docker
+        .build_image(
+            &image_name,
+            shards.iter().map(PathBuf::as_path).collect(),
+            llama_path,
+        )
+        .await?;
+
+    info!("Built docker image");
+    report::record_timing("docker build", started);
+    match docker.image_id(&image_name).await {
+        Ok(id) => report::update(|report| report.docker_image_id = Some(id)),
+        Err(e) => warn!("{:#}", e),
+    }
+    if args.docker_gpu {
+        info!(
+            "Run {} with `docker run --gpus all`, on a host with the NVIDIA Container Toolkit",
+            image_name
+        );
+    }
+
+    if let Some(compose_out) = args.compose_out.as_ref() {
+        compose::write(Path::new(compose_out), &image_name, port, args.docker_gpu)?;
+    }
+    Ok(image_name)
+}
+
+async fn build_llamafile(
+    args: &Args,
+    model_paths: &[PathBuf],
+    llama_path: &Path,
+    embedded_files: &[&Path],
+) -> anyhow::Result<PathBuf> {
+    info!("Building llamafile");
+    let started = Instant::now();
+    let mut llamafile_builder = LlamafileBuilder::new(
+        args.build_args
+            .llamafile_output_dir
+            .as_ref()
+            .map(From::from),
+        Some(llama_path.to_path_buf()),
+        args.build_args.zipalign_path.as_ref().map(From::from),
+        args.build_args.work_dir.as_ref().map(From::from),
+    )
+    .await
+    .context("Failed to initialize llamafile builder")?;
+    let mut build_args = server_args(args);
+    build_args.extend(args.build_args.llamafile_args.iter().cloned());
+    llamafile_builder.set_server_args(build_args);
+    let (shards, default_model_index) =
+        expand_shards(model_paths, args.build_args.default_model_index)?;
+    llamafile_builder.set_default_model_index(default_model_index);
+    llamafile_builder.set_address(args.host.clone(), args.port);
+    llamafile_builder.set_release_version(args.llamafile_version.clone());
+    llamafile_builder.set_server_asset(args.llamafile_asset.clone());
+    if args.build_args.keep_temp {
+        llamafile_builder.keep_temp();
+    }
+
+    let path: Option<PathBuf> = args.build_args.llamafile_output.as_ref().map(From::from);
+
+    let models: Vec<&Path> = shards.iter().map(PathBuf::as_path).collect();
+    let output = llamafile_builder
+        .build(&models, embedded_files, path)
+        .await?;
+
+    info!("Built llamafile at {}", output.display());
+    report::record_timing("llamafile build", started);
+
+    if args.build_args.smoke_test {
+        info!("Smoke testing {}", output.display());
+        let started = Instant::now();
+        runner::smoke_test(&output, SMOKE_TEST_TIMEOUT)
+            .await
+            .with_context(|| format!("Smoke test of {} failed", output.display()))?;
+        info!("Smoke test of {} passed", output.display());
+        report::record_timing("smoke test", started);
+    }
+
+    Ok(output)
+}
+
+/// Builds every entry of the spec, up to `jobs` at a time. Without `--continue-on-error`, no
+/// entry is started after the first failure.
+async fn apply_spec(args: &Args, apply_args: &apply::ApplyArgs) -> anyhow::Result<()> {
+    let spec = apply::Spec::load(Path::new(&apply_args.spec))?;
+    let jobs = apply_args.jobs.unwrap_or(spec.jobs).max(1);
+
+    // Entries share llamafile-server, so it's downloaded once up front.
+    let llama_path = PathBuf::from(
+        args.llamafile_server_path
+            .clone()
+            .unwrap_or_else(default_llamafile_server_path),
+    );
+    locate_llamafile_server(args, &llama_path).await?;
+
+    let credentials =
+        apply_args
+            .registry_username
+            .as_ref()
+            .map(|username| bollard::auth::DockerCredentials {
+                username: Some(username.clone()),
+                password: apply_args.registry_password.as_ref().map(|p| p.0.clone()),
+                ..Default::default()
+            });
+
+    let mut results = futures_util::stream::iter(&spec.models)
+        .map(|entry| {
+            let credentials = credentials.clone();
+            let llama_path = &llama_path;
+            async move {
+                info!("Applying {}", entry.name);
+                let result = apply_entry(args, entry, llama_path, credentials).await;
+                (entry, result)
+            }
+        })
+        .buffer_unordered(jobs);
+
+    let mut failed = Vec::new();
+    while let Some((entry, result)) = results.next().await {
+        match result {
+            Ok(()) => info!("{}: succeeded", entry.name),
+            Err(e) => {
+                error!("{}: failed: {:#}", entry.name, e);
+                failed.push(entry.name.as_str());
+                if !apply_args.continue_on_error {
+                    break;
+                }
+            }
+        }
+    }
+    drop(results);
+
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "{} of {} entries failed: {}",
+            failed.len(),
+            spec.models.len(),
+            failed.join(", ")
+        );
+    }
+    info!("Applied all {} entries", spec.models.len());
+    Ok(())
+}
+
+/// Builds and pushes one entry of a spec, parsed like a command line of its own. Environment
+/// variables are ignored so that they don't leak into every entry.
+async fn apply_entry(
+    parent: &Args,
+    entry: &apply::Entry,
+    llama_path: &Path,
+    credentials: Option<bollard::auth::DockerCredentials>,
+) -> anyhow::Result<()> {
+    let matches = Args::command()
+        .mut_args(|arg| arg.env(None::<&'static str>))
+        .try_get_matches_from(entry.argv())?;
+    let mut args = Args::from_arg_matches(&matches)?;
+    if args.model_dir.is_none() {
+        args.model_dir = parent.model_dir.clone();
+    }
+    args.llamafile_server_path = Some(llama_path.display().to_string());
+
+    let (model_paths, mut embedded_files, tokenizer) = locate_model(&args).await?;
+
+    if let Some(ctx_size) = args.ctx_size {
+        for model_path in &model_paths {
+            gguf::validate_context_size(model_path, ctx_size, args.strict)?;
+        }
+    }
+
+    for local_file in [&args.build_args.license_file, &args.build_args.readme_file]
+        .into_iter()
+        .flatten()
+    {
+        let local_file = PathBuf::from(local_file);
+        if !local_file.exists() {
+            anyhow::bail!("File path '{}' does not exist", local_file.display());
+        }
+        embedded_files.push(local_file);
+    }
+    embedded_files.extend(tokenizer);
+    let embedded_files: Vec<&Path> = embedded_files.iter().map(PathBuf::as_path).collect();
+
+    if args.build_args.build_llamafile {
+        build_llamafile(&args, &model_paths, llama_path, &embedded_files).await?;
+    }
+
+    if args.docker_build {
+        let image_name = build_docker_image(&args, &model_paths, llama_path).await?;
+        if entry.push {
+            docker::Docker::new(Vec::new(), !args.no_docker_init)?
+                .push_image(&image_name, credentials)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Arguments of the llamafile-server run by `--execute`, after the model.
+fn run_args(args: &Args) -> Vec<String> {
+    let mut run_args = server_args(args);
+    run_args.extend([
+        "--host".to_string(),
+        args.host.clone(),
+        "--port".to_string(),
+        args.port.to_string(),
+    ]);
+    run_args.extend(args.extra_server_args.iter().cloned());
+    run_args
+}
+
+/// Lists the shards of all the models, along with where the default model's first shard ends up
+/// among them.
+fn expand_shards(
+    model_paths: &[PathBuf],
+    default_model_index: usize,
+) -> anyhow::Result<(Vec<PathBuf>, usize)> {
+    if default_model_index >= model_paths.len() {
+        anyhow::bail!(
+            "Default model index {} is out of range, only {} models are given",
+            default_model_index,
+            model_paths.len()
+        );
+    }
+
+    let mut shards = Vec::new();
+    let mut shard_index = 0;
+    for (i, model_path) in model_paths.iter().enumerate() {
+        if i == default_model_index {
+            shard_index = shards.len();
+        }
+        shards.extend(models::shard_paths(model_path)?);
+    }
+    Ok((shards, shard_index))
+}
+
+/// `./llamafile-server`, with `.exe` on Windows so that it can be run there.
+fn default_llamafile_server_path() -> String {
+    format!(
+        "./{}",
+        llamafile_builder::executable_name("llamafile-server")
+    )
+}
+
+/// Arguments passed to llamafile-server wherever it runs: directly, in a llamafile or in docker.
+fn server_args(args: &Args) -> Vec<String> {
+    let mut server_args = Vec::new();
+    if let Some(ctx_size) = args.ctx_size {
+        server_args.extend(["-c".to_string(), ctx_size.to_string()]);
+    }
+    if let Some(batch_size) = args.batch_size {
+        server_args.extend(["-b".to_string(), batch_size.to_string()]);
+    }
+    server_args
+}
+
+fn tag_model(args: &Args, tag_args: &TagArgs) -> anyhow::Result<()> {
+    let files = open_models(args)?;
+    let key = files.model_key(Path::new(&tag_args.model))?;
+
+    let mut manifest = Manifest::load(files.base_dir())?;
+    let user = &mut manifest.entry(&key).user;
+    if let Some(note) = &tag_args.note {
+        user.note = Some(note.clone());
+    }
+    for tag in &tag_args.tags {
+        if !user.tags.contains(tag) {
+            user.tags.push(tag.clone());
+        }
+    }
+    user.tags.retain(|tag| !tag_args.untags.contains(tag));
+    manifest.save()?;
+
+    info!("Updated {}", key);
+    Ok(())
+}
+
+fn list_models(args: &Args, list_args: &ListArgs) -> anyhow::Result<()> {
+    let files = open_models(args)?;
+    let manifest = Manifest::load(files.base_dir())?;
+
+    for key in files.list_cached()? {
+        let user = manifest.get(&key).map(|entry| &entry.user);
+        let tags = user.map(|user| user.tags.as_slice()).unwrap_or_default();
+        if list_args
+            .tag
+            .as_ref()
+            .is_some_and(|tag| !tags.contains(tag))
+        {
+            continue;
+        }
+
+        let size = std::fs::metadata(files.base_dir().join(&key))?.len();
+        let mut line = format!("{}  {}", key, indicatif::HumanBytes(size));
+        if !tags.is_empty() {
+            line.push_str(&format!(" [{}]", tags.join(", ")));
+        }
+        if let Some(note) = user.and_then(|user| user.note.as_ref()) {
+            line.push_str(&format!(" - {}", note));
+        }
+        println!("{}", line);
+    }
+
+    // Interrupted downloads have no tags, and are resumed by the next download of the model.
+    if list_args.tag.is_none() {
+        for key in files.list_partial()? {
+            let size = std::fs::metadata(files.base_dir().join(&key))?.len();
+            println!(
+                "{}  {} (partial download)",
+                key,
+                indicatif::HumanBytes(size)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn open_models(args: &Args) -> anyhow::Result<Models> {
+    let mut models = Models::new(
+        args.model_dir.clone(),
+        args.hf_mirror.clone(),
+        args.refresh,
+        args.model_layout,
+    )?;
+    models.set_cache_max_size(args.cache_max_size);
+    models.set_hf_revision(args.args.hf_revision.clone());
+    models.set_check_gguf(!args.skip_gguf_check);
+    models.set_cache_mode(args.cache_mode);
+    models.set_check_disk_space(!args.skip_disk_space_check);
+    models.set_force_download(args.force_download);
+    if args.local_hf_cache {
+        models.set_hf_hub_cache(models::default_hf_hub_cache());
+    }
+    Ok(models)
+}
+
+/// Parses sizes such as `512M` or `100G`, in powers of 1024.
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, unit) = size.split_at(
+        size.find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(size.len()),
+    );
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size '{}'", size))?;
+    let multiplier: u64 = match unit
+        .trim()
+        .to_ascii_uppercase()
+        .trim_end_matches("IB")
+        .trim_end_matches('B')
+    {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("Unknown unit in size '{}'", size)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Resolves where the models of `model_source` are cached, without any network access, or `None`
+/// if any of them isn't.
+fn cached_model_paths(args: &Args, model_source: &ModelSource) -> Option<Vec<PathBuf>> {
+    let sources = match model_source.sources() {
+        Ok(sources) => sources,
+        Err(e) => crash(&format!("{:#}", e)),
+    };
+
+    let mut files: Option<Models> = None;
+    let mut paths = Vec::new();
+    for source in sources {
+        if let Source::File(file_path) = source {
+            let file_path = PathBuf::from(file_path);
+            paths.push(file_path.exists().then_some(file_path)?);
+            continue;
+        }
+
+        let files = match files.as_mut() {
+            Some(files) => files,
+            None => match open_models(args) {
+                Ok(mut opened) => {
+                    opened.set_hf_revision(model_source.hf_revision.clone());
+                    files.insert(opened)
+                }
+                Err(e) => crash(&format!("Failed to initialize models directory: {}", e)),
+            },
+        };
+        let path = match source {
+            Source::Hf { model, filename } => {
+                let Some(filename) = filename else {
+                    crash("--hf-file-name is required to check the cache without network access");
+                };
+                files.cached_hf_model(model, filename)
+            }
+            Source::Url(url) => match files.cached_model(url) {
+                Ok(path) => path,
+                Err(e) => crash(&format!("Failed to resolve model path: {}", e)),
+            },
+            Source::Manifest(_) => crash("--manifest-url can't be resolved without network access"),
+            Source::File(_) => unreachable!(),
+        };
+        paths.push(path?);
+    }
+    Some(paths)
+}
+
+/// Parses the arguments, without falling back to environment variables if `--no-env` is given.
+fn parse_args() -> (Args, clap::ArgMatches, config::ConfigFile) {
+    let mut command = Args::command();
+    let no_env = std::env::args_os().any(|arg| arg == "--no-env");
+    if no_env {
+        command = command.mut_args(|arg| arg.env(None::<&'static str>));
+    }
+
+    // The config file provides defaults for the arguments, so it's read before parsing them.
+    let config_file = config::ConfigFile::load(config_path(no_env).as_deref())
+        .and_then(|config_file| {
+            command = config_file.apply_defaults(command.clone())?;
+            Ok(config_file)
+        })
+        .unwrap_or_else(|e| {
+            command
+                .error(
+                    clap::error::ErrorKind::InvalidValue,
+                    format!("Failed to load config file: {:#}", e),
+                )
+                .exit()
+        });
+
+    let matches = command.get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    (args, matches, config_file)
+}
+
+/// The `--config` argument, or `$LAUNCHER_CONFIG`, found ahead of parsing the other arguments.
+fn config_path(no_env: bool) -> Option<String> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return args.next().map(|path| path.to_string_lossy().to_string());
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.to_string());
+        }
+    }
+
+    if no_env {
+        return None;
     }
+    std::env::var("LAUNCHER_CONFIG")
+        .ok()
+        .filter(|path| !path.is_empty())
 }
 
 fn crash(msg: &str) -> ! {
     error!("{}", msg);
+    http_client::log_retry_summary();
+    report::write(report::Status::Failure, Some(msg));
     error!("Exiting");
+    log_buffer::flush_to_stderr();
     exit(1);
 }
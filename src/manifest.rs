@@ -0,0 +1,100 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Metadata about the models of a models directory, keyed by their path relative to it.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Manifest {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(default)]
+    pub models: BTreeMap<String, ModelEntry>,
+    /// Digests docker base images were pinned to, keyed by their tag.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub base_images: BTreeMap<String, String>,
+    /// Blobs of the content-addressed store, relative to the models directory, keyed by their
+    /// sha256.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub blobs: BTreeMap<String, String>,
+    /// Recent download throughput in bytes per second, keyed by host.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub throughput: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ModelEntry {
+    /// Hugging Face repository the model was downloaded from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repo: Option<String>,
+
+    /// Checksum the model was verified against when it was downloaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+
+    /// When the model was last used, in seconds since the Unix epoch, for cache eviction.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<u64>,
+
+    /// Metadata set by the user, kept apart from what the launcher records so that
+    /// re-downloading a model doesn't lose it.
+    #[serde(default)]
+    pub user: UserMetadata,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct UserMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+impl Manifest {
+    pub fn load(models_dir: &Path) -> Result<Self> {
+        let path = models_dir.join(MANIFEST_FILE);
+        let mut manifest: Manifest = if path.exists() {
+            let json = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            serde_json::from_str(&json)
+                .with_context(|| format!("Failed to parse {}", path.display()))?
+        } else {
+            Manifest::default()
+        };
+
+        manifest.path = path;
+        Ok(manifest)
+    }
+
+    /// Writes the manifest to a temporary file renamed over the previous one, so that an
+    /// interrupted run never leaves a truncated manifest behind, like downloads.
+    pub fn save(&self) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let mut file = tempfile::NamedTempFile::new_in(dir)
+            .with_context(|| format!("Failed to create a temporary file in {}", dir.display()))?;
+        file.write_all(json.as_bytes())?;
+        file.as_file().sync_all()?;
+        file.persist(&self.path)
+            .with_context(|| format!("Failed to write {}", self.path.display()))?;
+        Ok(())
+    }
+
+    pub fn entry(&mut self, key: &str) -> &mut ModelEntry {
+        self.models.entry(key.to_string()).or_default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&ModelEntry> {
+        self.models.get(key)
+    }
+}
+
+/// Whether `file_name` is one of the launcher's own files rather than a model.
+pub fn is_manifest_file(file_name: &str) -> bool {
+    file_name == MANIFEST_FILE
+}
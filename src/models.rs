@@ -1,16 +1,77 @@
 use anyhow::{Context, Result};
-use log::{debug, info};
-use std::path::{Path, PathBuf};
+use futures_util::StreamExt;
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use crate::http_client::HttpClient;
+use crate::{
+    checksum, gguf,
+    http_client::{self, HttpClient},
+    manifest::{self, Manifest},
+};
+
+pub const HF_ENDPOINT: &str = "https://huggingface.co";
+const HF_DEFAULT_REVISION: &str = "main";
+const HF_LISTING_TTL: Duration = Duration::from_secs(10 * 60);
+/// Content-addressed store of [`CacheMode::Cas`], hidden so that its blobs aren't listed as models.
+const BLOBS_DIR: &str = ".blobs";
+/// Space left free on the disk after a download, for the files written next to models.
+const DISK_SPACE_MARGIN: u64 = 1024 * 1024 * 1024;
 
 pub struct Models {
     base_dir: PathBuf,
+    hf_mirror: Option<String>,
+    /// Branch, tag or commit of Hugging Face repositories.
+    hf_revision: String,
+    refresh: bool,
+    layout: Layout,
+    cache_max_size: Option<u64>,
+    /// Models used by this run, which are never evicted.
+    in_use: HashSet<String>,
+    /// Hub cache of the `huggingface_hub` Python library, checked before downloading.
+    hf_hub_cache: Option<PathBuf>,
+    /// Whether downloaded `.gguf` files are checked to start with the GGUF magic.
+    check_gguf: bool,
+    cache_mode: CacheMode,
+    /// Whether cached models are downloaded again.
+    force_download: bool,
+    /// Whether downloads are checked to fit on the disk before starting.
+    check_disk_space: bool,
     http_client: HttpClient,
 }
 
+/// How Hugging Face files are laid out in the models directory.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// `<organization>/<repository>/<file>`
+    #[default]
+    Nested,
+    /// `<file>`
+    Flat,
+}
+
+/// How downloaded Hugging Face files are stored.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Each file at its path, even when another repository has the same content
+    #[default]
+    Plain,
+    /// Files with a known sha256 in a content-addressed store, linked from their paths, so that
+    /// identical files of several repositories are downloaded and stored once
+    Cas,
+}
+
 impl Models {
-    pub fn new(basedir: Option<String>) -> Result<Self> {
+    pub fn new(
+        basedir: Option<String>,
+        hf_mirror: Option<String>,
+        refresh: bool,
+        layout: Layout,
+    ) -> Result<Self> {
         debug!("Creating LocalFiles");
         let basedir = basedir.unwrap_or_else(|| "./models/".to_string());
         let basedir = Path::new(&basedir);
@@ -24,56 +85,1341 @@ impl Models {
             )
         }
 
+        let mut http_client = HttpClient::new();
+        http_client.set_throughput_history(PathBuf::from(basedir));
+
         Ok(Self {
             base_dir: PathBuf::from(basedir),
-            http_client: HttpClient::new(),
+            hf_mirror,
+            hf_revision: HF_DEFAULT_REVISION.to_string(),
+            refresh,
+            layout,
+            cache_max_size: None,
+            in_use: HashSet::new(),
+            hf_hub_cache: None,
+            check_gguf: true,
+            cache_mode: CacheMode::default(),
+            force_download: false,
+            check_disk_space: true,
+            http_client,
+        })
+    }
+
+    /// Caps the size of the models directory: before a download that would exceed it, the least
+    /// recently used models are evicted.
+    pub fn set_cache_max_size(&mut self, max_size: Option<u64>) {
+        self.cache_max_size = max_size;
+    }
+
+    /// Uses Hugging Face files from `revision` rather than `main`. Files of other revisions are
+    /// cached separately.
+    pub fn set_hf_revision(&mut self, revision: Option<String>) {
+        self.hf_revision = revision.unwrap_or_else(|| HF_DEFAULT_REVISION.to_string());
+    }
+
+    /// Uses Hugging Face files from the `huggingface_hub` cache when they're there, rather than
+    /// downloading a second copy.
+    pub fn set_hf_hub_cache(&mut self, hf_hub_cache: Option<PathBuf>) {
+        self.hf_hub_cache = hf_hub_cache;
+    }
+
+    /// Skips checking that downloaded `.gguf` files are actually GGUF files.
+    pub fn set_check_gguf(&mut self, check_gguf: bool) {
+        self.check_gguf = check_gguf;
+    }
+
+    /// Stores Hugging Face files in a content-addressed store with [`CacheMode::Cas`]. Blobs
+    /// are kept when the paths linking to them are removed.
+    pub fn set_cache_mode(&mut self, cache_mode: CacheMode) {
+        self.cache_mode = cache_mode;
+    }
+
+    /// Downloads models again even when they're cached, replacing the cached copy once the
+    /// download completes.
+    pub fn set_force_download(&mut self, force_download: bool) {
+        self.force_download = force_download;
+    }
+
+    /// Skips checking that downloads fit on the disk before starting them.
+    pub fn set_check_disk_space(&mut self, check_disk_space: bool) {
+        self.check_disk_space = check_disk_space;
+    }
+
+    pub fn base_dir(&self) -> &Path {
+        &self.base_dir
+    }
+
+    /// Lists the cached models, as paths relative to the models directory.
+    pub fn list_cached(&self) -> Result<Vec<String>> {
+        self.list_files(|file_name| {
+            !file_name.ends_with(".part") && !manifest::is_manifest_file(file_name)
         })
     }
 
+    /// Lists the downloads that were interrupted, as paths of their `.part` files relative to
+    /// the models directory.
+    pub fn list_partial(&self) -> Result<Vec<String>> {
+        self.list_files(|file_name| file_name.ends_with(".part"))
+    }
+
+    /// Lists the files of the models directory matching `filter`, skipping hidden files.
+    fn list_files(&self, filter: impl Fn(&str) -> bool) -> Result<Vec<String>> {
+        let mut models = Vec::new();
+        let mut dirs = vec![self.base_dir.clone()];
+
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                let path = entry.path();
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                if file_name.starts_with('.') {
+                    continue;
+                }
+
+                if entry.file_type()?.is_dir() {
+                    dirs.push(path);
+                } else if filter(&file_name) {
+                    models.push(self.model_key(&path)?);
+                }
+            }
+        }
+
+        models.sort();
+        Ok(models)
+    }
+
+    /// Resolves `model`, either a path or a path relative to the models directory, to its key
+    /// in the manifest.
+    pub fn model_key(&self, model: &Path) -> Result<String> {
+        let base_dir = self.base_dir.canonicalize()?;
+        let path = if model.exists() {
+            model.canonicalize()?
+        } else {
+            self.base_dir.join(model).canonicalize().with_context(|| {
+                format!("Model '{}' is not in the models directory", model.display())
+            })?
+        };
+
+        let relative = path.strip_prefix(&base_dir).with_context(|| {
+            format!("Model '{}' is not in the models directory", model.display())
+        })?;
+
+        Ok(relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"))
+    }
+
+    /// Moves the cached Hugging Face files into the `to` layout, updating the manifest. Files
+    /// whose destination already exists are left in place.
+    pub fn migrate(&self, to: Layout, dry_run: bool) -> Result<()> {
+        let mut manifest = Manifest::load(&self.base_dir)?;
+        let mut moved = 0;
+
+        for key in self.list_cached()? {
+            let from = match to {
+                Layout::Nested => Layout::Flat,
+                Layout::Flat => Layout::Nested,
+            };
+            let Some((repo, revision, filename)) = parse_hf_key(from, &key) else {
+                continue;
+            };
+            let repo = match repo {
+                Some(repo) => manifest
+                    .entry(&key)
+                    .repo
+                    .get_or_insert_with(|| repo.to_string())
+                    .clone(),
+                None => match manifest.get(&key).and_then(|e| e.repo.clone()) {
+                    Some(repo) => repo,
+                    None => {
+                        warn!("Skipping {}: its repository is unknown", key);
+                        continue;
+                    }
+                },
+            };
+            let destination = hf_key(to, revision, &repo, filename);
+
+            let source_path = self.base_dir.join(&key);
+            let destination_path = self.base_dir.join(&destination);
+            if destination_path.exists() {
+                warn!("Skipping {}: {} already exists", key, destination);
+                continue;
+            }
+
+            info!("Moving {} to {}", key, destination);
+            moved += 1;
+            if dry_run {
+                continue;
+            }
+
+            std::fs::create_dir_all(destination_path.parent().unwrap())?;
+            std::fs::rename(&source_path, &destination_path)?;
+            if let Some(entry) = manifest.models.remove(&key) {
+                manifest.models.insert(destination, entry);
+            }
+
+            self.remove_empty_dirs(&source_path);
+        }
+
+        if dry_run {
+            info!("Would move {} files", moved);
+        } else {
+            manifest.save()?;
+            info!("Moved {} files", moved);
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the cached model `name`, either a path or a path relative to the models
+    /// directory, along with its manifest entry.
+    pub fn remove(&self, name: &str) -> Result<()> {
+        // Resolving the key makes sure the file is in the models directory.
+        let key = self.model_key(Path::new(name))?;
+        let path = self.base_dir.join(&key);
+        if !path.is_file() || manifest::is_manifest_file(&key) {
+            anyhow::bail!("'{}' is not a cached model", name);
+        }
+
+        info!("Removing {}", key);
+        std::fs::remove_file(&path)?;
+        self.remove_empty_dirs(&path);
+
+        let mut manifest = Manifest::load(&self.base_dir)?;
+        if manifest.models.remove(&key).is_some() {
+            manifest.save()?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the cached models and interrupted downloads last modified more than `older_than`
+    /// ago.
+    pub fn prune(&self, older_than: Duration, dry_run: bool) -> Result<()> {
+        let mut keys = self.list_cached()?;
+        keys.extend(self.list_partial()?);
+
+        let mut freed = 0;
+        for key in keys {
+            let metadata = std::fs::metadata(self.base_dir.join(&key))?;
+            let age = metadata.modified()?.elapsed().unwrap_or_default();
+            if age < older_than {
+                continue;
+            }
+
+            freed += metadata.len();
+            if dry_run {
+                info!("Would remove {}", key);
+            } else if key.ends_with(".part") {
+                info!("Removing {}", key);
+                std::fs::remove_file(self.base_dir.join(&key))?;
+                self.remove_empty_dirs(&self.base_dir.join(&key));
+            } else {
+                self.remove(&key)?;
+            }
+        }
+
+        if dry_run {
+            info!("Would free {}", indicatif::HumanBytes(freed));
+        } else {
+            info!("Freed {}", indicatif::HumanBytes(freed));
+        }
+        Ok(())
+    }
+
+    /// Cleans up the directories left empty after removing `path`, up to the models directory.
+    fn remove_empty_dirs(&self, path: &Path) {
+        let mut dir = path.parent();
+        while let Some(parent) = dir.filter(|dir| *dir != self.base_dir) {
+            if std::fs::remove_dir(parent).is_err() {
+                break;
+            }
+            dir = parent.parent();
+        }
+    }
+
+    /// Checks that a freshly downloaded `.gguf` file is one, deleting it otherwise so the next
+    /// run downloads it again.
+    fn check_download(&self, path: &Path) -> Result<()> {
+        let is_gguf_name = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("gguf"));
+        if !self.check_gguf || !is_gguf_name {
+            return Ok(());
+        }
+
+        if let Err(e) = gguf::check_magic(path) {
+            std::fs::remove_file(path)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Where a download of `path` is written: `path` itself, or with a cached copy that is
+    /// downloaded again, a hidden file next to it, so that a failed download leaves the copy in
+    /// place. See [`finish_download`].
+    fn download_target(&self, path: &Path) -> PathBuf {
+        if !path.exists() {
+            return path.to_path_buf();
+        }
+        let mut file_name = std::ffi::OsString::from(".");
+        file_name.push(path.file_name().unwrap_or_default());
+        file_name.push(".download");
+        path.with_file_name(file_name)
+    }
+
+    /// Blob of the content-addressed store with this sha256, with [`CacheMode::Cas`].
+    fn cached_blob(&self, sha256: &str) -> Option<PathBuf> {
+        if self.cache_mode != CacheMode::Cas {
+            return None;
+        }
+        let manifest = Manifest::load(&self.base_dir).ok()?;
+        let blob = self.base_dir.join(manifest.blobs.get(sha256)?);
+        blob.is_file().then_some(blob)
+    }
+
+    /// Moves a verified download into the content-addressed store with [`CacheMode::Cas`],
+    /// linking it back to its path.
+    fn store_blob(&self, sha256: &str, path: &Path) -> Result<()> {
+        if self.cache_mode != CacheMode::Cas {
+            return Ok(());
+        }
+
+        let key = format!("{}/{}", BLOBS_DIR, sha256);
+        let blob = self.base_dir.join(&key);
+        std::fs::create_dir_all(blob.parent().unwrap())?;
+        std::fs::rename(path, &blob)
+            .with_context(|| format!("Failed to move {} to {}", path.display(), blob.display()))?;
+        link_blob(&blob, path)?;
+
+        let mut manifest = Manifest::load(&self.base_dir)?;
+        manifest.blobs.insert(sha256.to_string(), key);
+        manifest.save()
+    }
+
+    /// Records that the model at `key` is used by this run.
+    fn touch(&mut self, key: &str) -> Result<()> {
+        self.in_use.insert(key.to_string());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut manifest = Manifest::load(&self.base_dir)?;
+        manifest.entry(key).last_used = Some(now);
+        manifest.save()
+    }
+
+    /// Evicts the least recently used models until `incoming` bytes fit under the cache size
+    /// cap, if there is one, then checks that they fit on the disk.
+    async fn make_room(&mut self, url: &str, incoming: Option<u64>) -> Result<()> {
+        if self.cache_max_size.is_none() && !self.check_disk_space {
+            return Ok(());
+        }
+        let incoming = match incoming {
+            Some(incoming) => incoming,
+            None => match self.http_client.content_length(url).await {
+                Ok(Some(incoming)) => incoming,
+                Ok(None) => 0,
+                Err(e) => {
+                    warn!("Failed to get the size of {}: {}", url, e);
+                    0
+                }
+            },
+        };
+
+        if let Some(max_size) = self.cache_max_size {
+            self.evict(url, incoming, max_size)?;
+        }
+        if self.check_disk_space {
+            self.check_free_space(url, incoming)?;
+        }
+        Ok(())
+    }
+
+    fn evict(&mut self, url: &str, incoming: u64, max_size: u64) -> Result<()> {
+        let mut cached = Vec::new();
+        for key in self.list_cached()? {
+            let size = std::fs::metadata(self.base_dir.join(&key))?.len();
+            cached.push((key, size));
+        }
+        let mut total: u64 = cached.iter().map(|(_, size)| size).sum();
+        if total + incoming <= max_size {
+            return Ok(());
+        }
+
+        let mut manifest = Manifest::load(&self.base_dir)?;
+        // Models that were never used since access times are tracked go first.
+        cached.sort_by_key(|(key, _)| manifest.get(key).and_then(|entry| entry.last_used));
+
+        for (key, size) in cached {
+            if total + incoming <= max_size {
+                break;
+            }
+            if self.in_use.contains(&key) {
+                continue;
+            }
+
+            info!(
+                "Evicting {} ({}) to stay under the cache size limit",
+                key,
+                indicatif::HumanBytes(size)
+            );
+            std::fs::remove_file(self.base_dir.join(&key))?;
+            manifest.models.remove(&key);
+            total -= size;
+        }
+        manifest.save()?;
+
+        if total + incoming > max_size {
+            warn!(
+                "Downloading {} exceeds the cache size limit, as the remaining models are in use",
+                url
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Fails if `incoming` bytes, plus [`DISK_SPACE_MARGIN`], don't fit on the file system of
+    /// the models directory, rather than filling it up halfway through the download.
+    fn check_free_space(&self, url: &str, incoming: u64) -> Result<()> {
+        let Some(available) = available_space(&self.base_dir) else {
+            return Ok(());
+        };
+        if incoming + DISK_SPACE_MARGIN > available {
+            anyhow::bail!(
+                "Not enough disk space to download {}: it needs {} plus a margin of {}, but only {} are available in {}. Free up space, or pass --skip-disk-space-check",
+                url,
+                indicatif::HumanBytes(incoming),
+                indicatif::HumanBytes(DISK_SPACE_MARGIN),
+                indicatif::HumanBytes(available),
+                self.base_dir.display()
+            );
+        }
+        Ok(())
+    }
+
     fn exists(&self, filename: &str) -> bool {
         std::path::Path::new(&self.base_dir).join(filename).exists()
     }
 
     fn exists_hf(&self, model: &str, filename: &str) -> bool {
-        self.exists(format!("{}/{}", model, filename).as_str())
+        self.hf_path(model, filename).exists()
+    }
+
+    /// Path of a Hugging Face file relative to the models directory, which depends on the
+    /// layout. Files of revisions other than `main` are kept apart, under `<model>@<revision>`
+    /// or `<revision>`.
+    fn hf_key(&self, model: &str, filename: &str) -> String {
+        hf_key(self.layout, &self.hf_revision, model, filename)
+    }
+
+    /// Where a Hugging Face file is cached, whether or not it has been downloaded yet.
+    pub fn hf_path(&self, model: &str, filename: &str) -> PathBuf {
+        self.base_dir.join(self.hf_key(model, filename))
     }
 
     pub async fn get_hf_model(&mut self, model: &str, filename: &str) -> Result<PathBuf> {
-        if !self.exists_hf(model, filename) {
-            info!("Downloading {}/{}", model, filename);
-            let mut model_dir = self.base_dir.clone();
-            model_dir.push(model);
-            std::fs::create_dir_all(&model_dir)?;
-            model_dir.push(filename);
-            let url = format!(
-                "https://huggingface.co/{}/resolve/main/{}?download=true",
-                model, filename
+        if let Some(path) = self
+            .hf_hub_cached(model, filename)
+            .filter(|_| !self.force_download)
+        {
+            info!(
+                "Found {}/{} in the Hugging Face hub cache at {}",
+                model,
+                filename,
+                path.display()
             );
+            return Ok(path);
+        }
+
+        if self.force_download || !self.exists_hf(model, filename) {
+            let model_dir = self.hf_path(model, filename);
+            let expected = match self.hf_sha256(model, filename).await {
+                Ok(expected) => expected,
+                Err(e) => {
+                    warn!("Failed to get checksum of {}/{}: {}", model, filename, e);
+                    None
+                }
+            };
+
+            if let Some(blob) = expected
+                .as_deref()
+                .and_then(|sha256| self.cached_blob(sha256))
+                .filter(|_| !self.force_download)
+            {
+                info!(
+                    "Found {}/{} in the content-addressed store, linking it",
+                    model, filename
+                );
+                std::fs::create_dir_all(model_dir.parent().unwrap())?;
+                link_blob(&blob, &model_dir)?;
+            } else {
+                info!("Downloading {}/{}", model, filename);
+                self.make_room(
+                    &hf_resolve_url(HF_ENDPOINT, model, &self.hf_revision, filename),
+                    None,
+                )
+                .await?;
+                std::fs::create_dir_all(model_dir.parent().unwrap())?;
 
-            self.http_client
-                .download_to(&url, &model_dir, false)
-                .await?
+                if expected.is_none() {
+                    warn!(
+                        "No checksum available for {}/{}, skipping verification",
+                        model, filename
+                    );
+                }
+
+                let target = self.download_target(&model_dir);
+                self.download_hf(model, filename, &target, expected.as_deref())
+                    .await?;
+                self.check_download(&target)?;
+                finish_download(&target, &model_dir)?;
+                if let Some(sha256) = expected.as_deref() {
+                    self.store_blob(sha256, &model_dir)?;
+                }
+            }
+
+            let mut manifest = Manifest::load(&self.base_dir)?;
+            let entry = manifest.entry(&self.hf_key(model, filename));
+            entry.repo = Some(model.to_string());
+            entry.sha256 = expected;
+            manifest.save()?;
         } else {
             info!("Found {}/{} locally", model, filename);
         }
 
-        Ok(self.base_dir.join(model).join(filename))
+        let key = self.hf_key(model, filename);
+        self.touch(&key)?;
+        Ok(self.hf_path(model, filename))
+    }
+
+    /// Downloads a Hugging Face model like [`Models::get_hf_model`], along with the other shards
+    /// when `filename` is one of a sharded model. Returns the path of the first shard, which
+    /// llama.cpp loads the others from.
+    pub async fn get_hf_model_shards(&mut self, model: &str, filename: &str) -> Result<PathBuf> {
+        let Some(names) = shard_names(filename) else {
+            return self.get_hf_model(model, filename).await;
+        };
+
+        let uncached: Vec<&String> = names
+            .iter()
+            .filter(|name| self.cached_hf_model(model, name).is_none())
+            .collect();
+        if !uncached.is_empty() {
+            let available = self.list_hf_files(model).await?;
+            let missing: Vec<&str> = uncached
+                .iter()
+                .filter(|name| !available.contains(name))
+                .map(|name| name.as_str())
+                .collect();
+            if !missing.is_empty() {
+                anyhow::bail!(
+                    "Repository {} is missing shards of {}: {}",
+                    model,
+                    filename,
+                    missing.join(", ")
+                );
+            }
+        }
+
+        info!("Getting {} shards of {}", names.len(), filename);
+        let mut paths = Vec::new();
+        for name in &names {
+            paths.push(self.get_hf_model(model, name).await?);
+        }
+
+        // All shards must end up in the same directory, which isn't the case when some come
+        // from the Hugging Face hub cache and others were downloaded.
+        shard_paths(&paths[0])?;
+        Ok(paths.swap_remove(0))
+    }
+
+    /// Fetches an auxiliary file (README, LICENSE, ...) from a Hugging Face repository,
+    /// returning `None` if the repository doesn't contain it.
+    pub async fn get_hf_file_if_exists(
+        &mut self,
+        model: &str,
+        filename: &str,
+    ) -> Result<Option<PathBuf>> {
+        if self.exists_hf(model, filename) {
+            info!("Found {}/{} locally", model, filename);
+            return Ok(Some(self.hf_path(model, filename)));
+        }
+
+        let url = hf_resolve_url(HF_ENDPOINT, model, &self.hf_revision, filename);
+        if !self.http_client.exists(&url).await? {
+            return Ok(None);
+        }
+
+        self.get_hf_model(model, filename).await.map(Some)
+    }
+
+    /// Downloads a file from Hugging Face, falling back to the CDN target of the `resolve`
+    /// redirect, then to the configured mirror, when the previous endpoint fails.
+    async fn download_hf(
+        &mut self,
+        model: &str,
+        filename: &str,
+        path: &Path,
+        expected_sha256: Option<&str>,
+    ) -> Result<()> {
+        let resolve_url = hf_resolve_url(HF_ENDPOINT, model, &self.hf_revision, filename);
+        let mut last_err = match self
+            .http_client
+            .download_to_verified(&resolve_url, path, false, expected_sha256)
+            .await
+        {
+            Ok(()) => {
+                info!("Downloaded {}/{} from {}", model, filename, HF_ENDPOINT);
+                return Ok(());
+            }
+            Err(e) if http_client::is_unauthorized(&e) => return Err(hf_access_denied(e, model)),
+            Err(e) => e,
+        };
+        warn!("Failed to download from {}: {}", HF_ENDPOINT, last_err);
+
+        let mut fallbacks = Vec::new();
+        match self.http_client.redirect_location(&resolve_url).await {
+            Ok(Some(cdn_url)) => fallbacks.push(cdn_url),
+            Ok(None) => debug!("{} did not redirect", resolve_url),
+            Err(e) => debug!("Failed to resolve redirect of {}: {}", resolve_url, e),
+        }
+        if let Some(mirror) = &self.hf_mirror {
+            fallbacks.push(hf_resolve_url(mirror, model, &self.hf_revision, filename));
+        }
+
+        for url in fallbacks {
+            let endpoint = endpoint_of(&url);
+            info!("Retrying {}/{} from {}", model, filename, endpoint);
+            http_client::record_retry(&url);
+            match self
+                .http_client
+                .download_to_verified(&url, path, false, expected_sha256)
+                .await
+            {
+                Ok(()) => {
+                    info!("Downloaded {}/{} from {}", model, filename, endpoint);
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("Failed to download from {}: {}", endpoint, e);
+                    last_err = e;
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
+    async fn hf_siblings(&mut self, model: &str) -> Result<Vec<HfSibling>> {
+        let cache_path = self.base_dir.join(".cache").join("hf-api").join(format!(
+            "{}@{}.json",
+            model.replace('/', "--"),
+            self.hf_revision.replace('/', "--")
+        ));
+
+        let json = match self.read_fresh_cache(&cache_path) {
+            Some(json) => {
+                debug!("Using cached listing of repository {}", model);
+                json
+            }
+            None => {
+                let url = format!(
+                    "{}/api/models/{}/revision/{}?blobs=true",
+                    HF_ENDPOINT, model, self.hf_revision
+                );
+                let json = self.http_client.get_text(&url).await.map_err(|e| {
+                    if http_client::is_unauthorized(&e) {
+                        hf_access_denied(e, model)
+                    } else {
+                        e.context(format!("Failed to list files of repository {}", model))
+                    }
+                })?;
+
+                if let Err(e) = std::fs::create_dir_all(cache_path.parent().unwrap())
+                    .and_then(|_| std::fs::write(&cache_path, &json))
+                {
+                    warn!("Failed to cache listing of repository {}: {}", model, e);
+                }
+                json
+            }
+        };
+
+        let info: HfModelInfo = serde_json::from_str(&json)
+            .with_context(|| format!("Failed to parse listing of repository {}", model))?;
+
+        Ok(info.siblings)
+    }
+
+    fn read_fresh_cache(&self, path: &Path) -> Option<String> {
+        if self.refresh {
+            return None;
+        }
+
+        let age = std::fs::metadata(path)
+            .ok()?
+            .modified()
+            .ok()?
+            .elapsed()
+            .ok()?;
+        if age > HF_LISTING_TTL {
+            return None;
+        }
+
+        std::fs::read_to_string(path).ok()
+    }
+
+    pub async fn list_hf_files(&mut self, model: &str) -> Result<Vec<String>> {
+        Ok(self
+            .hf_siblings(model)
+            .await?
+            .into_iter()
+            .map(|sibling| sibling.rfilename)
+            .collect())
+    }
+
+    /// Returns the sha256 Hugging Face publishes for an LFS file, if any.
+    pub async fn hf_sha256(&mut self, model: &str, filename: &str) -> Result<Option<String>> {
+        Ok(self
+            .hf_siblings(model)
+            .await?
+            .into_iter()
+            .find(|sibling| sibling.rfilename == filename)
+            .and_then(|sibling| sibling.lfs)
+            .map(|lfs| lfs.sha256))
+    }
+
+    /// Checks the cached models against their recorded checksums, or those published by their
+    /// Hugging Face repository, hashing up to `jobs` files concurrently.
+    pub async fn verify(&mut self, jobs: usize) -> Result<()> {
+        let manifest = Manifest::load(&self.base_dir)?;
+        let mut to_hash = Vec::new();
+        let mut unknown = 0;
+
+        for key in self.list_cached()? {
+            let entry = manifest.get(&key);
+            let mut expected = entry.and_then(|entry| entry.sha256.clone());
+            if let (None, Some(repo)) = (&expected, entry.and_then(|entry| entry.repo.clone())) {
+                let filename = key.rsplit('/').next().unwrap_or(&key);
+                expected = match self.hf_sha256(&repo, filename).await {
+                    Ok(expected) => expected,
+                    Err(e) => {
+                        warn!("Failed to get checksum of {}: {}", key, e);
+                        None
+                    }
+                };
+            }
+
+            match expected {
+                Some(expected) => to_hash.push((key, expected)),
+                None => {
+                    warn!("No checksum known for {}, skipping", key);
+                    unknown += 1;
+                }
+            }
+        }
+
+        // Hashing is CPU-bound, so it runs on the blocking thread pool.
+        let base_dir = self.base_dir.clone();
+        let results: Vec<(String, Result<()>)> = futures_util::stream::iter(to_hash)
+            .map(|(key, expected)| {
+                let path = base_dir.join(&key);
+                async move {
+                    let result = tokio::task::spawn_blocking(move || {
+                        checksum::verify_sha256(&path, Some(&expected))
+                    })
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|result| result);
+                    (key, result)
+                }
+            })
+            .buffer_unordered(jobs.max(1))
+            .collect()
+            .await;
+
+        let mut failed = 0;
+        for (key, result) in &results {
+            if let Err(e) = result {
+                warn!("{}: {}", key, e);
+                failed += 1;
+            }
+        }
+
+        info!(
+            "Verified {} models: {} passed, {} failed, {} without a checksum",
+            results.len() + unknown,
+            results.len() - failed,
+            failed,
+            unknown
+        );
+        if failed > 0 {
+            anyhow::bail!("{} models failed verification", failed);
+        }
+
+        Ok(())
+    }
+
+    /// Finds the GGUF file of a repository, which must contain exactly one.
+    pub async fn find_hf_gguf(&mut self, model: &str) -> Result<String> {
+        let ggufs: Vec<String> = self
+            .list_hf_files(model)
+            .await?
+            .into_iter()
+            .filter(|filename| filename.ends_with(".gguf"))
+            // Sharded models are picked by their first shard.
+            .filter(|filename| shard_names(filename).map_or(true, |names| names[0] == *filename))
+            .collect();
+
+        match ggufs.as_slice() {
+            [] => anyhow::bail!("Repository {} contains no GGUF file", model),
+            [gguf] => {
+                info!("Found {} in repository {}", gguf, model);
+                Ok(gguf.clone())
+            }
+            _ => anyhow::bail!(
+                "Repository {} contains multiple GGUF files, pick one with --hf-file-name: {}",
+                model,
+                ggufs.join(", ")
+            ),
+        }
+    }
+
+    /// URL a Hugging Face file is downloaded from.
+    pub fn hf_url(&self, model: &str, filename: &str) -> String {
+        hf_resolve_url(HF_ENDPOINT, model, &self.hf_revision, filename)
+    }
+
+    /// Size of a remote file, if the server announces it.
+    pub async fn remote_size(&mut self, url: &str) -> Result<Option<u64>> {
+        self.http_client.content_length(url).await
+    }
+
+    /// Fetches the [`ModelDescriptor`] at `manifest_url`.
+    pub async fn model_descriptor(&mut self, manifest_url: &str) -> Result<ModelDescriptor> {
+        self.http_client
+            .get(manifest_url)
+            .await
+            .with_context(|| format!("Failed to get model manifest from {}", manifest_url))
+    }
+
+    /// Returns where a Hugging Face model is cached, if it is, without any network access.
+    pub fn cached_hf_model(&self, model: &str, filename: &str) -> Option<PathBuf> {
+        self.hf_hub_cached(model, filename).or_else(|| {
+            self.exists_hf(model, filename)
+                .then(|| self.hf_path(model, filename))
+        })
+    }
+
+    /// Finds a file in the hub cache, in the snapshot the revision points to, or is for a
+    /// commit. For `main`, any snapshot containing the file is used otherwise. Snapshot files
+    /// are symlinks into `blobs`, or copies where symlinks aren't available such as on Windows,
+    /// so the snapshot path is used either way, keeping the file's name.
+    fn hf_hub_cached(&self, model: &str, filename: &str) -> Option<PathBuf> {
+        let repo_dir = self
+            .hf_hub_cache
+            .as_ref()?
+            .join(format!("models--{}", model.replace('/', "--")));
+        let snapshots = repo_dir.join("snapshots");
+
+        let commit = std::fs::read_to_string(repo_dir.join("refs").join(&self.hf_revision))
+            .map(|commit| commit.trim().to_string())
+            .unwrap_or_else(|_| self.hf_revision.clone());
+        let path = snapshots.join(commit).join(filename);
+        if path.exists() {
+            return Some(path);
+        }
+        if self.hf_revision != HF_DEFAULT_REVISION {
+            return None;
+        }
+
+        std::fs::read_dir(&snapshots)
+            .ok()?
+            .flatten()
+            .map(|snapshot| snapshot.path().join(filename))
+            .find(|path| path.exists())
+    }
+
+    /// Returns where a model downloaded from `url` is cached, if it is, without any network
+    /// access.
+    pub fn cached_model(&self, url: &str) -> Result<Option<PathBuf>> {
+        let filename = url_filename(url);
+        Ok(self.exists(&filename).then(|| self.url_path(url)))
+    }
+
+    /// Where a model downloaded from `url` is cached, whether or not it has been downloaded yet.
+    pub fn url_path(&self, url: &str) -> PathBuf {
+        self.base_dir.join(url_filename(url))
     }
 
     pub async fn get_model(&mut self, url: &str) -> Result<PathBuf> {
-        let filename = url
-            .split('/')
-            .last()
-            .context("Couldn't extract filename from URL")?;
+        let filename = url_filename(url);
+        // The one path the model is downloaded to, checked and returned at.
+        let path = self.url_path(url);
 
-        if !self.exists(filename) {
+        if self.force_download || !path.exists() {
             info!("Downloading {} to {}", url, filename);
-            let filename = self.base_dir.join(filename);
-            self.http_client.download_to(url, &filename, false).await?;
+            self.make_room(url, None).await?;
+            let target = self.download_target(&path);
+            self.http_client.download_to(url, &target, false).await?;
+            self.check_download(&target)?;
+            finish_download(&target, &path)?;
         } else {
             info!("Found {} locally", filename);
         }
 
-        Ok(self.base_dir.join(filename))
+        self.touch(&filename)?;
+        Ok(path)
+    }
+
+    /// Downloads the model described by the [`ModelDescriptor`] at `manifest_url`.
+    pub async fn get_manifest_model(&mut self, manifest_url: &str) -> Result<PathBuf> {
+        let descriptor = self.model_descriptor(manifest_url).await?;
+        debug!("Model manifest: {:?}", descriptor);
+
+        let filename = descriptor.filename(manifest_url)?;
+        let path = self.base_dir.join(&filename);
+
+        if !self.force_download && self.exists(&filename) {
+            info!("Found {} locally", filename);
+            self.touch(&filename)?;
+            return Ok(path);
+        }
+
+        info!("Downloading {} to {}", descriptor.url, filename);
+        self.make_room(&descriptor.url, descriptor.size).await?;
+        if descriptor.sha256.is_none() {
+            warn!(
+                "No checksum available for {}, skipping verification",
+                descriptor.url
+            );
+        }
+        let target = self.download_target(&path);
+        self.http_client
+            .download_to_verified(
+                &descriptor.url,
+                &target,
+                false,
+                descriptor.sha256.as_deref(),
+            )
+            .await?;
+
+        self.check_download(&target)?;
+
+        if let Some(expected) = descriptor.size {
+            let actual = std::fs::metadata(&target)?.len();
+            if actual != expected {
+                std::fs::remove_file(&target)?;
+                anyhow::bail!(
+                    "Size mismatch for '{}': expected {} bytes, got {}",
+                    path.display(),
+                    expected,
+                    actual
+                );
+            }
+        }
+        finish_download(&target, &path)?;
+
+        if let Some(sha256) = descriptor.sha256.clone() {
+            let mut manifest = Manifest::load(&self.base_dir)?;
+            manifest.entry(&filename).sha256 = Some(sha256);
+            manifest.save()?;
+        }
+
+        self.touch(&filename)?;
+        Ok(path)
+    }
+}
+
+/// A model described by a JSON document, which some hosts publish at a stable URL:
+///
+/// ```json
+/// { "name": "model.gguf", "url": "https://...", "sha256": "...", "size": 4368439584 }
+/// ```
+///
+/// Only `url` is required. `name` defaults to the last segment of `url`, and the download is
+/// only verified against the `sha256` and `size` that are given.
+#[derive(serde::Deserialize, Debug)]
+pub struct ModelDescriptor {
+    pub name: Option<String>,
+    pub url: String,
+    pub sha256: Option<String>,
+    pub size: Option<u64>,
+}
+
+impl ModelDescriptor {
+    /// Name the model is cached under.
+    pub fn filename(&self, manifest_url: &str) -> Result<String> {
+        match self.name.as_deref() {
+            Some(name) if is_clean_filename(name) => Ok(name.to_string()),
+            Some(name) => anyhow::bail!("Invalid model name '{}' in {}", name, manifest_url),
+            None => Ok(url_filename(&self.url)),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct HfModelInfo {
+    siblings: Vec<HfSibling>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct HfSibling {
+    rfilename: String,
+    lfs: Option<HfLfs>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct HfLfs {
+    sha256: String,
+}
+
+/// Where `huggingface_hub` caches downloads: `$HF_HUB_CACHE`, `$HF_HOME/hub`, or
+/// `~/.cache/huggingface/hub`.
+pub fn default_hf_hub_cache() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("HF_HUB_CACHE").filter(|dir| !dir.is_empty()) {
+        return Some(PathBuf::from(dir));
+    }
+    if let Some(dir) = std::env::var_os("HF_HOME").filter(|dir| !dir.is_empty()) {
+        return Some(PathBuf::from(dir).join("hub"));
+    }
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(
+        PathBuf::from(home)
+            .join(".cache")
+            .join("huggingface")
+            .join("hub"),
+    )
+}
+
+/// Names of all the shards of a model split into `<base>-00001-of-00003.gguf` style files, in
+/// order, or `None` if `filename` isn't a shard.
+pub fn shard_names(filename: &str) -> Option<Vec<String>> {
+    let suffix = shard_suffix(filename)?;
+    let base = &filename[..filename.len() - suffix.len()];
+    let (index, total, extension) = parse_shard_suffix(suffix)?;
+    let total_count: usize = total.parse().ok()?;
+
+    Some(
+        (1..=total_count)
+            .map(|i| {
+                format!(
+                    "{}-{:0width$}-of-{}.{}",
+                    base,
+                    i,
+                    total,
+                    extension,
+                    width = index.len()
+                )
+            })
+            .collect(),
+    )
+}
+
+/// The `-00001-of-00003.gguf` suffix of a shard.
+pub fn shard_suffix(filename: &str) -> Option<&str> {
+    let (stem, _) = filename.rsplit_once('.')?;
+    let mut parts = stem.rsplitn(4, '-');
+    let (_, _, _, base) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    let suffix = &filename[base.len()..];
+    parse_shard_suffix(suffix).map(|_| suffix)
+}
+
+/// Splits a `-00001-of-00003.gguf` suffix into its parts, checking the index is in range.
+fn parse_shard_suffix(suffix: &str) -> Option<(&str, &str, &str)> {
+    let (stem, extension) = suffix.rsplit_once('.')?;
+    let mut parts = stem.split('-');
+    let (empty, index, of, total) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    let is_number = |token: &str| !token.is_empty() && token.chars().all(|c| c.is_ascii_digit());
+    if !empty.is_empty()
+        || of != "of"
+        || !is_number(index)
+        || !is_number(total)
+        || parts.next().is_some()
+    {
+        return None;
+    }
+
+    let (index_number, total_number): (usize, usize) = (index.parse().ok()?, total.parse().ok()?);
+    (1..=total_number)
+        .contains(&index_number)
+        .then_some((index, total, extension))
+}
+
+/// Paths of all the shards of the model at `model_path`, which must be next to each other, or
+/// just `model_path` when it isn't sharded.
+pub fn shard_paths(model_path: &Path) -> Result<Vec<PathBuf>> {
+    let Some(names) = model_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(shard_names)
+    else {
+        return Ok(vec![model_path.to_path_buf()]);
+    };
+
+    let dir = model_path.parent().unwrap_or(Path::new(""));
+    let paths: Vec<PathBuf> = names.iter().map(|name| dir.join(name)).collect();
+    let missing: Vec<&str> = names
+        .iter()
+        .zip(&paths)
+        .filter(|(_, path)| !path.exists())
+        .map(|(name, _)| name.as_str())
+        .collect();
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "Shards missing next to {}: {}",
+            model_path.display(),
+            missing.join(", ")
+        );
+    }
+
+    Ok(paths)
+}
+
+/// Space available to unprivileged users on the file system of `path`, where it can be queried.
+#[cfg(unix)]
+// The field types vary between platforms.
+#[allow(clippy::unnecessary_cast)]
+fn available_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Replaces `path` with the completed download at `target`, see [`Models::download_target`].
+fn finish_download(target: &Path, path: &Path) -> Result<()> {
+    if target != path {
+        std::fs::rename(target, path)
+            .with_context(|| format!("Failed to replace {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Links `path` to `blob`, with a hard link so that removing either leaves the other intact, or
+/// a symbolic link on file systems without them.
+fn link_blob(blob: &Path, path: &Path) -> Result<()> {
+    if std::fs::hard_link(blob, path).is_ok() {
+        return Ok(());
+    }
+
+    #[cfg(unix)]
+    let linked = std::os::unix::fs::symlink(blob.canonicalize()?, path);
+    #[cfg(not(unix))]
+    let linked = Err(std::io::Error::from(std::io::ErrorKind::Unsupported));
+    linked.with_context(|| format!("Failed to link {} to {}", path.display(), blob.display()))
+}
+
+fn hf_access_denied(e: anyhow::Error, model: &str) -> anyhow::Error {
+    e.context(format!(
+        "Access to {} was denied, pass --hf-token or set HF_TOKEN to a token that can read it",
+        model
+    ))
+}
+
+fn hf_resolve_url(endpoint: &str, model: &str, revision: &str, filename: &str) -> String {
+    format!(
+        "{}/{}/resolve/{}/{}?download=true",
+        endpoint.trim_end_matches('/'),
+        model,
+        revision,
+        filename
+    )
+}
+
+/// Name a model downloaded from `url` is cached under: the last segment of its path, without
+/// the query string, or when it isn't a clean file name, `model-` followed by a hash of the URL.
+fn url_filename(url: &str) -> String {
+    let segment = match reqwest::Url::parse(url) {
+        Ok(parsed) => parsed
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .map(ToString::to_string),
+        Err(_) => url
+            .split(['?', '#'])
+            .next()
+            .and_then(|path| path.rsplit('/').next())
+            .map(ToString::to_string),
+    };
+
+    match segment {
+        Some(segment) if is_clean_filename(&segment) => segment,
+        _ => {
+            let hash = format!("{:x}", Sha256::digest(url.as_bytes()));
+            format!("model-{}", &hash[..16])
+        }
+    }
+}
+
+/// Whether `name` is a single, visible file name, which stays within the models directory.
+fn is_clean_filename(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && !name.contains(['/', '\\'])
+        && !name.chars().any(char::is_control)
+}
+
+fn endpoint_of(url: &str) -> String {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(ToString::to_string))
+        .unwrap_or_else(|| url.to_string())
+}
+
+/// Key of a Hugging Face file in `layout`, qualified by the revision unless it's the main one.
+fn hf_key(layout: Layout, revision: &str, model: &str, filename: &str) -> String {
+    if revision == HF_DEFAULT_REVISION {
+        return match layout {
+            Layout::Nested => format!("{}/{}", model, filename),
+            Layout::Flat => filename.to_string(),
+        };
+    }
+
+    let revision = revision.replace('/', "--");
+    match layout {
+        Layout::Nested => format!("{}@{}/{}", model, revision, filename),
+        Layout::Flat => format!("{}/{}", revision, filename),
+    }
+}
+
+/// Splits a key built by [`hf_key`] into its repository, which flat keys don't have, revision
+/// and file name. Returns `None` for keys that aren't in `layout`.
+fn parse_hf_key(layout: Layout, key: &str) -> Option<(Option<&str>, &str, &str)> {
+    match layout {
+        Layout::Nested => {
+            let (dir, filename) = key.rsplit_once('/')?;
+            if !dir.contains('/') {
+                return None;
+            }
+            let (repo, revision) = dir.split_once('@').unwrap_or((dir, HF_DEFAULT_REVISION));
+            Some((Some(repo), revision, filename))
+        }
+        Layout::Flat => match key.split_once('/') {
+            None => Some((None, HF_DEFAULT_REVISION, key)),
+            Some((_, filename)) if filename.contains('/') => None,
+            Some((revision, filename)) => Some((None, revision, filename)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Answers every request on a loopback port with `body`, returning the server's URL.
+    async fn serve(body: &'static [u8]) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut request = vec![0; 4096];
+                    let read = stream.read(&mut request).await.unwrap_or(0);
+                    let head = request[..read].starts_with(b"HEAD");
+                    let mut response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    )
+                    .into_bytes();
+                    if !head {
+                        response.extend_from_slice(body);
+                    }
+                    let _ = stream.write_all(&response).await;
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    fn models_in(dir: &Path) -> Models {
+        Models::new(
+            Some(dir.display().to_string()),
+            None,
+            false,
+            Layout::default(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn get_model_returns_the_downloaded_file() {
+        let url = serve(b"model").await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut models = models_in(dir.path());
+
+        let path = models
+            .get_model(&format!("{}/model.bin", url))
+            .await
+            .unwrap();
+
+        assert_eq!(path, dir.path().join("model.bin"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"model");
+    }
+
+    #[test]
+    fn url_filename_drops_the_query_string() {
+        assert_eq!(
+            url_filename("https://x/model.gguf?download=true"),
+            "model.gguf"
+        );
+    }
+
+    #[test]
+    fn url_path_stays_within_the_models_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let models = models_in(dir.path());
+        assert_eq!(
+            models.url_path("https://x/model.gguf?download=true"),
+            dir.path().join("model.gguf")
+        );
+        assert_eq!(models.url_path("https://x/").parent(), Some(dir.path()));
+    }
+
+    #[test]
+    fn url_filename_falls_back_to_a_hash() {
+        for url in ["https://x/", "https://x/models/..", "not a url/.."] {
+            let filename = url_filename(url);
+            assert!(filename.starts_with("model-"), "{} -> {}", url, filename);
+            assert!(is_clean_filename(&filename));
+        }
+        assert_eq!(url_filename("https://x/"), url_filename("https://x/"));
+        assert_ne!(url_filename("https://x/"), url_filename("https://y/"));
+    }
+
+    #[test]
+    fn is_clean_filename_rejects_traversal() {
+        for name in ["", ".", "..", "../model.gguf", "a/b", "a\\b", ".hidden"] {
+            assert!(!is_clean_filename(name), "{}", name);
+        }
+        assert!(is_clean_filename("model.gguf"));
+    }
+
+    #[test]
+    fn migrate_round_trips_revisions() {
+        let dir = tempfile::tempdir().unwrap();
+        let models = models_in(dir.path());
+        for key in ["org/repo/model.gguf", "org/repo@v1.0/model.gguf"] {
+            let path = dir.path().join(key);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, key).unwrap();
+        }
+
+        models.migrate(Layout::Flat, false).unwrap();
+        let mut flat = models.list_cached().unwrap();
+        flat.sort();
+        assert_eq!(flat, ["model.gguf", "v1.0/model.gguf"]);
+
+        models.migrate(Layout::Nested, false).unwrap();
+        for key in ["org/repo/model.gguf", "org/repo@v1.0/model.gguf"] {
+            assert_eq!(std::fs::read_to_string(dir.path().join(key)).unwrap(), key);
+        }
     }
 }
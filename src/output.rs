@@ -0,0 +1,49 @@
+use anyhow::{Context, Result};
+use std::{
+    fs::{File, OpenOptions},
+    io::ErrorKind,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static OVERWRITE: AtomicBool = AtomicBool::new(false);
+
+/// Allows replacing existing outputs, which are otherwise never overwritten.
+pub fn set_overwrite(overwrite: bool) {
+    OVERWRITE.store(overwrite, Ordering::Relaxed);
+}
+
+pub fn overwrite() -> bool {
+    OVERWRITE.load(Ordering::Relaxed)
+}
+
+/// Fails if `path` exists and overwriting isn't allowed.
+pub fn check_writable(path: &Path) -> Result<()> {
+    if !overwrite() && path.exists() {
+        return Err(already_exists(path));
+    }
+    Ok(())
+}
+
+/// Opens `path` for writing with `options`, truncating it if it exists and overwriting is
+/// allowed, failing otherwise.
+pub fn create(path: &Path, options: &mut OpenOptions) -> Result<File> {
+    options.write(true);
+    if overwrite() {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+
+    match options.open(path) {
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => Err(already_exists(path)),
+        file => file.with_context(|| format!("Failed to create '{}'", path.display())),
+    }
+}
+
+fn already_exists(path: &Path) -> anyhow::Error {
+    anyhow::anyhow!(
+        "'{}' already exists, pass --overwrite to replace it",
+        path.display()
+    )
+}
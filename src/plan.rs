@@ -0,0 +1,80 @@
+use anyhow::Result;
+use indicatif::HumanBytes;
+use serde::Serialize;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum OutputFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+/// What a run would do, built by `--dry-run` without downloading, building or running anything.
+#[derive(Serialize, Debug, Default)]
+pub struct Plan {
+    pub steps: Vec<Step>,
+    /// Sum of the known sizes of the downloads.
+    pub download_bytes: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Step {
+    #[serde(rename = "type")]
+    pub kind: StepKind,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    /// Whether the step downloads its inputs, rather than using cached copies.
+    pub download: bool,
+    pub estimated_bytes: Option<u64>,
+    /// Command line of the step, for those running one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum StepKind {
+    LocateModel,
+    LocateLlamafileServer,
+    BuildLlamafile,
+    BuildDocker,
+    Run,
+}
+
+impl Plan {
+    pub fn push(&mut self, step: Step) {
+        if step.download {
+            self.download_bytes += step.estimated_bytes.unwrap_or(0);
+        }
+        self.steps.push(step);
+    }
+
+    pub fn print(&self, format: OutputFormat) -> Result<()> {
+        match format {
+            OutputFormat::Human => {
+                for step in &self.steps {
+                    let action = if step.download { "download" } else { "use" };
+                    println!("{:?}:", step.kind);
+                    for input in &step.inputs {
+                        match step.estimated_bytes {
+                            Some(bytes) => {
+                                println!("  {} {} ({})", action, input, HumanBytes(bytes))
+                            }
+                            None => println!("  {} {}", action, input),
+                        }
+                    }
+                    for output in &step.outputs {
+                        println!("  write {}", output);
+                    }
+                    if let Some(command) = &step.command {
+                        println!("  run {}", command);
+                    }
+                }
+                println!("Total download: {}", HumanBytes(self.download_bytes));
+            }
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(self)?),
+        }
+
+        Ok(())
+    }
+}
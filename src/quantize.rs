@@ -0,0 +1,115 @@
+use anyhow::{Context, Result};
+use indicatif::HumanBytes;
+use log::info;
+use std::path::Path;
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+use crate::{
+    gguf,
+    llamafile_builder::{GithubReleaseAsset, LlamafileBuilder},
+    output,
+};
+
+/// Quantization types accepted by llamafile-quantize.
+const QUANT_TYPES: &[&str] = &[
+    "Q4_0", "Q4_1", "Q5_0", "Q5_1", "IQ2_XXS", "IQ2_XS", "IQ2_S", "IQ2_M", "IQ1_S", "IQ1_M",
+    "Q2_K", "Q2_K_S", "IQ3_XXS", "IQ3_XS", "IQ3_S", "IQ3_M", "Q3_K", "Q3_K_S", "Q3_K_M", "Q3_K_L",
+    "IQ4_NL", "IQ4_XS", "Q4_K", "Q4_K_S", "Q4_K_M", "Q5_K", "Q5_K_S", "Q5_K_M", "Q6_K", "Q8_0",
+    "F16", "BF16", "F32", "COPY",
+];
+
+#[cfg(not(windows))]
+const DEFAULT_QUANTIZE_PATH: &str = "./llamafile-quantize";
+#[cfg(windows)]
+const DEFAULT_QUANTIZE_PATH: &str = "./llamafile-quantize.exe";
+
+#[derive(Debug, clap::Args)]
+pub struct QuantizeArgs {
+    #[arg(help = "GGUF file to quantize")]
+    input: String,
+
+    #[arg(help = "Quantized GGUF file to write")]
+    output: String,
+
+    #[arg(long = "type", help = "Quantization type, e.g. Q5_K_M")]
+    quant_type: String,
+
+    #[arg(
+        long,
+        env,
+        default_value = DEFAULT_QUANTIZE_PATH,
+        help = "Path to llamafile-quantize, downloaded if missing"
+    )]
+    quantize_path: String,
+}
+
+pub async fn quantize(args: &QuantizeArgs) -> Result<()> {
+    let quant_type = args.quant_type.to_ascii_uppercase();
+    if !QUANT_TYPES.contains(&quant_type.as_str()) {
+        anyhow::bail!(
+            "Unknown quantization type '{}', expected one of: {}",
+            args.quant_type,
+            QUANT_TYPES.join(", ")
+        );
+    }
+
+    let input = Path::new(&args.input);
+    let output = Path::new(&args.output);
+    if !gguf::is_gguf(input)? {
+        anyhow::bail!("'{}' is not a GGUF file", input.display());
+    }
+    output::check_writable(output)?;
+
+    let quantize_path = Path::new(&args.quantize_path);
+    if !quantize_path.exists() {
+        info!("Downloading llamafile-quantize");
+        LlamafileBuilder::new(None, None, None, None)
+            .await?
+            .download_llamafile_github_release_into(GithubReleaseAsset::Quantize, quantize_path)
+            .await
+            .context("Failed to download llamafile-quantize")?;
+    }
+
+    #[cfg(unix)]
+    std::fs::set_permissions(quantize_path, std::fs::Permissions::from_mode(0o755))?;
+
+    info!(
+        "Quantizing {} to {} as {}..",
+        input.display(),
+        output.display(),
+        quant_type
+    );
+    let status = tokio::process::Command::new(quantize_path)
+        .arg(input)
+        .arg(output)
+        .arg(&quant_type)
+        .spawn()
+        .with_context(|| format!("Failed to start {}", quantize_path.display()))?
+        .wait()
+        .await?;
+    if !status.success() {
+        anyhow::bail!("llamafile-quantize exited with {}", status);
+    }
+
+    if !gguf::is_gguf(output)? {
+        anyhow::bail!(
+            "llamafile-quantize didn't write a GGUF file to '{}'",
+            output.display()
+        );
+    }
+
+    let input_size = std::fs::metadata(input)?.len();
+    let output_size = std::fs::metadata(output)?.len();
+    info!(
+        "Quantized {} ({}) to {} ({}), {:.0}% of the original size",
+        input.display(),
+        HumanBytes(input_size),
+        output.display(),
+        HumanBytes(output_size),
+        output_size as f64 / input_size.max(1) as f64 * 100.0
+    );
+
+    Ok(())
+}
@@ -0,0 +1,194 @@
+use anyhow::Result;
+use log::{error, info};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+static REPORT: Mutex<Option<Report>> = Mutex::new(None);
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Markdown,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Success,
+    Failure,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Report {
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    #[serde(skip)]
+    print: bool,
+    #[serde(skip)]
+    format: ReportFormat,
+    #[serde(skip)]
+    started: Option<Instant>,
+
+    pub status: Option<Status>,
+    pub error: Option<String>,
+    pub model_source: Option<String>,
+    pub model_path: Option<PathBuf>,
+    pub model_size: Option<u64>,
+    pub llamafile_server_path: Option<PathBuf>,
+    pub llamafile_output: Option<PathBuf>,
+    pub docker_image: Option<String>,
+    pub docker_image_id: Option<String>,
+    pub docker_base_image: Option<String>,
+    pub timings: Vec<Timing>,
+    /// Retries per host, see [`crate::http_client::record_retry`].
+    pub retries: BTreeMap<String, u32>,
+    pub total_seconds: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Timing {
+    pub step: String,
+    pub seconds: f64,
+}
+
+/// Starts collecting facts about this run, to be written to `path` and, with `print`, to stdout
+/// as JSON by [`write`].
+pub fn init(path: Option<PathBuf>, format: ReportFormat, print: bool) {
+    *REPORT.lock().unwrap() = Some(Report {
+        path,
+        print,
+        format,
+        started: Some(Instant::now()),
+        ..Default::default()
+    });
+}
+
+/// Records facts into the report, if one was requested.
+pub fn update(f: impl FnOnce(&mut Report)) {
+    if let Some(report) = REPORT.lock().unwrap().as_mut() {
+        f(report);
+    }
+}
+
+pub fn record_timing(step: &str, started: Instant) {
+    let elapsed = started.elapsed();
+    update(|report| {
+        report.timings.push(Timing {
+            step: step.to_string(),
+            seconds: elapsed.as_secs_f64(),
+        })
+    });
+}
+
+/// Writes the report, if one was requested. Errors are logged rather than returned so that
+/// writing the report never masks the outcome of the run.
+pub fn write(status: Status, error: Option<&str>) {
+    let Some(mut report) = REPORT.lock().unwrap().take() else {
+        return;
+    };
+
+    report.status = Some(status);
+    report.error = error.map(ToString::to_string);
+    report.retries = crate::http_client::retries();
+    report.total_seconds = report
+        .started
+        .map(|started| started.elapsed())
+        .unwrap_or(Duration::ZERO)
+        .as_secs_f64();
+
+    if let Some(path) = report.path.as_ref() {
+        match report.write_to_file(path) {
+            Ok(()) => info!("Wrote report to {}", path.display()),
+            Err(e) => error!("Failed to write report to {}: {}", path.display(), e),
+        }
+    }
+
+    if report.print {
+        match serde_json::to_string_pretty(&report) {
+            Ok(json) => println!("{}", json),
+            Err(e) => error!("Failed to print the result: {}", e),
+        }
+    }
+}
+
+impl Report {
+    fn write_to_file(&self, path: &Path) -> Result<()> {
+        let contents = match self.format {
+            ReportFormat::Json => serde_json::to_string_pretty(self)?,
+            ReportFormat::Markdown => self.to_markdown()?,
+        };
+
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+
+    fn to_markdown(&self) -> Result<String> {
+        let mut md = String::from("# Launcher report\n\n| | |\n|---|---|\n");
+
+        let status = match self.status {
+            Some(Status::Success) => "success",
+            Some(Status::Failure) => "failure",
+            None => "unknown",
+        };
+        writeln!(md, "| Status | {} |", status)?;
+
+        let rows = [
+            ("Error", self.error.clone()),
+            ("Model source", self.model_source.clone()),
+            (
+                "Model path",
+                self.model_path.as_ref().map(|p| p.display().to_string()),
+            ),
+            (
+                "Model size",
+                self.model_size.map(|s| format!("{} bytes", s)),
+            ),
+            (
+                "llamafile-server",
+                self.llamafile_server_path
+                    .as_ref()
+                    .map(|p| p.display().to_string()),
+            ),
+            (
+                "Llamafile output",
+                self.llamafile_output
+                    .as_ref()
+                    .map(|p| p.display().to_string()),
+            ),
+            ("Docker image", self.docker_image.clone()),
+            ("Docker image id", self.docker_image_id.clone()),
+            ("Docker base image", self.docker_base_image.clone()),
+        ];
+
+        for (name, value) in rows {
+            if let Some(value) = value {
+                writeln!(md, "| {} | {} |", name, value)?;
+            }
+        }
+
+        writeln!(md, "| Total time | {:.2}s |", self.total_seconds)?;
+
+        if !self.retries.is_empty() {
+            md.push_str("\n## Retries\n\n| Host | Retries |\n|---|---|\n");
+            for (host, count) in &self.retries {
+                writeln!(md, "| {} | {} |", host, count)?;
+            }
+        }
+
+        if !self.timings.is_empty() {
+            md.push_str("\n## Timings\n\n| Step | Seconds |\n|---|---|\n");
+            for timing in &self.timings {
+                writeln!(md, "| {} | {:.2} |", timing.step, timing.seconds)?;
+            }
+        }
+
+        Ok(md)
+    }
+}
@@ -0,0 +1,279 @@
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use std::{
+    path::Path,
+    process::{ExitStatus, Stdio},
+    time::{Duration, Instant},
+};
+use tokio::{io::AsyncReadExt, process::Child};
+
+use crate::{http_client::HttpClient, llamafile_builder};
+
+/// How long the server may take to stop after SIGTERM, before it's killed.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+pub struct Runner {
+    llama_path: String,
+    server_flag: bool,
+    server_args: Vec<String>,
+    wait_ready: Option<(String, Duration)>,
+}
+
+impl Runner {
+    pub fn new(llama_path: String) -> Result<Self> {
+        if !Path::new(&llama_path).exists() {
+            return Err(anyhow::anyhow!(format!(
+                "Llama path '{}' does not exist",
+                llama_path
+            )));
+        }
+
+        let server_flag = llamafile_builder::needs_server_flag(Path::new(&llama_path));
+        Ok(Self {
+            llama_path,
+            server_flag,
+            server_args: Vec::new(),
+            wait_ready: None,
+        })
+    }
+
+    /// Arguments added to every command, after the model.
+    pub fn set_server_args(&mut self, server_args: Vec<String>) {
+        self.server_args = server_args;
+    }
+
+    /// Makes [`Runner::run`] poll the server at `base_url` until it's healthy and log its URL,
+    /// stopping it if that takes longer than `timeout`.
+    pub fn set_wait_ready(&mut self, base_url: String, timeout: Duration) {
+        self.wait_ready = Some((base_url, timeout));
+    }
+
+    /// Builds the command running the model, for callers that need to add arguments or
+    /// manage the process themselves.
+    pub fn command(&self, model_path: &Path) -> tokio::process::Command {
+        let mut command = tokio::process::Command::new(&self.llama_path);
+        if self.server_flag {
+            command.arg("--server");
+        }
+        command.arg("-m").arg(model_path).args(&self.server_args);
+        command
+    }
+
+    /// Like [`Runner::command`], listening on `host` and `port` instead of the address given in
+    /// the server arguments.
+    pub fn command_at(&self, model_path: &Path, host: &str, port: u16) -> tokio::process::Command {
+        let mut command = tokio::process::Command::new(&self.llama_path);
+        if self.server_flag {
+            command.arg("--server");
+        }
+        command
+            .arg("-m")
+            .arg(model_path)
+            .args(without_address(&self.server_args))
+            .arg("--host")
+            .arg(host)
+            .arg("--port")
+            .arg(port.to_string());
+        command
+    }
+
+    /// Builds the command running the model on a loopback port, with its output discarded, for
+    /// the launcher to query it.
+    pub fn local_command(&self, model_path: &Path, port: u16) -> tokio::process::Command {
+        let mut command = self.command_at(model_path, "127.0.0.1", port);
+        command
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true);
+        command
+    }
+
+    /// Runs the server until it exits, or until Ctrl-C stops it gracefully.
+    pub async fn run(&self, model_path: &Path) -> Result<()> {
+        let mut command = self.command(model_path);
+        // In its own process group, the server doesn't get the terminal's SIGINT, and its
+        // sub-processes can be stopped along with it.
+        #[cfg(unix)]
+        command.process_group(0);
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("Failed to start {}", self.llama_path))?;
+
+        let ready = async {
+            if let Some((base_url, timeout)) = self.wait_ready.as_ref() {
+                wait_healthy(base_url, *timeout).await?;
+                info!("Server ready at {}", base_url);
+            }
+            std::future::pending::<Result<()>>().await
+        };
+
+        let status = tokio::select! {
+            status = child.wait() => status?,
+            Err(e) = ready => {
+                terminate(&mut child).await?;
+                return Err(e);
+            }
+            _ = tokio::signal::ctrl_c() => {
+                info!("Stopping {}..", self.llama_path);
+                let status = terminate(&mut child).await?;
+                info!("Stopped {} ({})", self.llama_path, status);
+                return Ok(());
+            }
+        };
+        if !status.success() {
+            anyhow::bail!("{} exited with {}", self.llama_path, status);
+        }
+
+        Ok(())
+    }
+}
+
+/// `server_args` without their `--host` and `--port`, so that they're given once.
+fn without_address(server_args: &[String]) -> Vec<&String> {
+    let mut args = Vec::new();
+    let mut iter = server_args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--host" | "--port" => {
+                iter.next();
+            }
+            _ if arg.starts_with("--host=") || arg.starts_with("--port=") => {}
+            _ => args.push(arg),
+        }
+    }
+    args
+}
+
+/// Formats a command as a shell command line, for users to reproduce it.
+pub fn command_line(command: &tokio::process::Command) -> String {
+    let command = command.as_std();
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| shell_quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn shell_quote(arg: &str) -> String {
+    let safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-./:=,@%+".contains(c));
+    if safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Polls the server's health endpoint until it answers successfully.
+pub async fn wait_healthy(base_url: &str, timeout: Duration) -> Result<()> {
+    let mut http_client = HttpClient::new();
+    let health_url = format!("{}/health", base_url);
+    let started = Instant::now();
+
+    loop {
+        match http_client.status(&health_url).await {
+            Ok(status) if status.is_success() => return Ok(()),
+            Ok(status) => debug!("Server not ready yet: {}", status),
+            Err(e) => debug!("Server not reachable yet: {}", e),
+        }
+
+        if started.elapsed() > timeout {
+            anyhow::bail!(
+                "Server at {} did not become healthy within {}s",
+                base_url,
+                timeout.as_secs()
+            );
+        }
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+/// Asks `child`, and on Unix its process group, to stop with SIGTERM, then kills them if they
+/// are still running after [`SHUTDOWN_GRACE_PERIOD`].
+async fn terminate(child: &mut Child) -> Result<ExitStatus> {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        let group = -(pid as libc::pid_t);
+        unsafe { libc::kill(group, libc::SIGTERM) };
+        match tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, child.wait()).await {
+            Ok(status) => return Ok(status?),
+            Err(_) => {
+                warn!(
+                    "Server didn't stop within {}s, killing it",
+                    SHUTDOWN_GRACE_PERIOD.as_secs()
+                );
+                unsafe { libc::kill(group, libc::SIGKILL) };
+                return Ok(child.wait().await?);
+            }
+        }
+    }
+
+    child.kill().await?;
+    Ok(child.wait().await?)
+}
+
+/// A loopback port no server listens on, assigned by the system, so that a server started on
+/// it is the one answering.
+pub fn free_port() -> Result<u16> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")
+        .context("Failed to find a free port on the loopback interface")?;
+    Ok(listener.local_addr()?.port())
+}
+
+/// Fails if something already listens on the loopback `port`, as it would answer the requests
+/// meant for the server.
+pub fn check_port_free(port: u16) -> Result<()> {
+    std::net::TcpListener::bind(("127.0.0.1", port))
+        .with_context(|| format!("Port {} is already in use", port))?;
+    Ok(())
+}
+
+/// Waits until the spawned `server` is healthy, failing as soon as it exits.
+pub async fn wait_started(
+    server: &mut tokio::process::Child,
+    base_url: &str,
+    timeout: Duration,
+) -> Result<()> {
+    tokio::select! {
+        status = server.wait() => Err(match status {
+            Ok(status) => anyhow::anyhow!("Exited early with {}", status),
+            Err(e) => e.into(),
+        }),
+        healthy = wait_healthy(base_url, timeout) => healthy,
+    }
+}
+
+/// Starts a built llamafile, which embeds its model and arguments, and waits until its server
+/// is healthy. Fails with the llamafile's stderr if it exits or never becomes healthy.
+pub async fn smoke_test(llamafile: &Path, timeout: Duration) -> Result<()> {
+    let port = free_port()?;
+    let mut child = tokio::process::Command::new(llamafile)
+        .arg("--host")
+        .arg("127.0.0.1")
+        .arg("--port")
+        .arg(port.to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to start {}", llamafile.display()))?;
+
+    // Drain stderr while the server runs, so that it never blocks on a full pipe.
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr = tokio::spawn(async move {
+        let mut output = String::new();
+        let _ = stderr.read_to_string(&mut output).await;
+        output
+    });
+
+    let base_url = format!("http://127.0.0.1:{}", port);
+    let result = wait_started(&mut child, &base_url, timeout).await;
+
+    let _ = child.kill().await;
+    let stderr = stderr.await.unwrap_or_default();
+
+    result.map_err(|e| anyhow::anyhow!("{}\n{}", e, stderr.trim_end()))
+}
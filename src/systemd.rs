@@ -0,0 +1,117 @@
+use anyhow::{Context, Result};
+use log::info;
+use std::{fmt::Write as _, path::Path};
+
+use crate::output;
+
+#[derive(Debug, clap::Args)]
+pub struct SystemdArgs {
+    #[arg(help = "Local model file path")]
+    model: String,
+
+    #[arg(long, help = "Write the unit to this file instead of stdout")]
+    out: Option<String>,
+
+    #[arg(long, default_value = "0.0.0.0", help = "Host the server listens on")]
+    host: String,
+
+    #[arg(long, default_value_t = 8080, help = "Port the server listens on")]
+    port: u16,
+
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Generate a user unit instead of a system unit"
+    )]
+    user_unit: bool,
+
+    #[arg(
+        long,
+        help = "User running the server, for system units",
+        conflicts_with("user_unit")
+    )]
+    run_as: Option<String>,
+
+    #[arg(long, help = "Memory limit of the service, e.g. 16G")]
+    memory_max: Option<String>,
+
+    #[arg(long, help = "CPU quota of the service, e.g. 400%")]
+    cpu_quota: Option<String>,
+}
+
+pub fn generate(llama_path: &Path, args: &SystemdArgs) -> Result<()> {
+    let model_path = Path::new(&args.model)
+        .canonicalize()
+        .with_context(|| format!("File path '{}' does not exist", args.model))?;
+    let llama_path = llama_path
+        .canonicalize()
+        .with_context(|| format!("Llama path '{}' does not exist", llama_path.display()))?;
+
+    let unit = unit(&llama_path, &model_path, args)?;
+
+    match &args.out {
+        Some(out) => {
+            output::check_writable(Path::new(out))?;
+            std::fs::write(out, unit).with_context(|| format!("Failed to write {}", out))?;
+            info!("Wrote systemd unit to {}", out);
+        }
+        None => print!("{}", unit),
+    }
+
+    Ok(())
+}
+
+fn unit(llama_path: &Path, model_path: &Path, args: &SystemdArgs) -> Result<String> {
+    let mut unit = String::new();
+
+    writeln!(unit, "[Unit]")?;
+    writeln!(
+        unit,
+        "Description=llamafile server for {}",
+        model_path.file_name().unwrap().to_string_lossy()
+    )?;
+    writeln!(unit, "After=network-online.target")?;
+    writeln!(unit, "Wants=network-online.target")?;
+
+    writeln!(unit, "\n[Service]")?;
+    writeln!(unit, "Type=simple")?;
+    writeln!(
+        unit,
+        "ExecStart=/bin/sh {} -m {} --host {} --port {}",
+        quote(llama_path),
+        quote(model_path),
+        args.host,
+        args.port
+    )?;
+    writeln!(unit, "Restart=on-failure")?;
+    writeln!(unit, "RestartSec=5")?;
+    if let Some(user) = &args.run_as {
+        writeln!(unit, "User={}", user)?;
+    }
+    if let Some(memory_max) = &args.memory_max {
+        writeln!(unit, "MemoryMax={}", memory_max)?;
+    }
+    if let Some(cpu_quota) = &args.cpu_quota {
+        writeln!(unit, "CPUQuota={}", cpu_quota)?;
+    }
+    writeln!(unit, "NoNewPrivileges=true")?;
+
+    writeln!(unit, "\n[Install]")?;
+    if args.user_unit {
+        writeln!(unit, "WantedBy=default.target")?;
+    } else {
+        writeln!(unit, "WantedBy=multi-user.target")?;
+    }
+
+    Ok(unit)
+}
+
+fn quote(path: &Path) -> String {
+    format!(
+        "\"{}\"",
+        path.display()
+            .to_string()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+    )
+}